@@ -0,0 +1,434 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Contributed by: Patrick Oppel (patrick.oppel94@gmail.com)
+//
+
+//! `#[cubeos_service]` attribute for the `impl` block of a subsystem
+//!
+//! ```rust,ignore
+//! #[cubeos_service(service = example_service, error = Error)]
+//! impl Subsystem {
+//!     #[query]
+//!     pub fn get_value(&self, get: ExampleEnum) -> Result<ExampleResult> { .. }
+//!     #[query(id = 0x0120, out = GroundResult)]
+//!     pub fn get_status(&self) -> Result<Status> { .. }
+//!     #[mutation(timeout = 500, retries = 2, backoff = 100, idempotent)]
+//!     pub fn set_value(&mut self, set: ExampleInput) -> Result<()> { .. }
+//! }
+//! ```
+//!
+//! The methods marked `#[query]` or `#[mutation]` become the commands of the service.
+//! From this one definition the attribute generates
+//! - `mod service` with the UDP dispatcher, or the ground terminal if the crate is built
+//!   with its `terminal` feature,
+//! - `mod app` with the app client, if the crate is built with its `app` feature.
+//!
+//! The `impl` block itself is left out of terminal builds, so the ground terminal does not
+//! need the subsystem and its dependencies.
+//!
+//! Commands are either all `async fn`s, which generate the asynchronous dispatcher
+//! and an app client with `async fn`s, or all synchronous.
+//!
+//! Options of the attribute:
+//! - `service`: name of the service in the config file, required for the app client
+//! - `error`: error type of the subsystem, defaults to `Error`
+//! - `app`: name of the app client, defaults to the name of the subsystem
+//!
+//! Options of `#[query]` and `#[mutation]`:
+//! - `name`: name of the CommandID, defaults to the method name in UpperCamelCase
+//! - `id`: explicit CommandID, unique among the commands
+//! - `timeout`: timeout of the command in milliseconds
+//! - `out`: ground output type of a query, defaults to the reply type
+//! - `retries`: number of times the app client sends the command again
+//! - `backoff`: delay of the app client before the first retry in milliseconds
+//! - `idempotent`: the app client may send a mutation again if there is no reply,
+//!   queries are always idempotent
+//!
+//! The attribute expands to `service_macro!` and `app_macro!`. Queries are declared before
+//! mutations, so commands without an explicit `id` are numbered queries first.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use std::collections::HashSet;
+use syn::meta::ParseNestedMeta;
+use syn::{
+    parse_macro_input, parse_quote, Attribute, Error, FnArg, GenericArgument, Ident, ImplItem,
+    ImplItemFn, ItemImpl, LitInt, Meta, Pat, Path, PathArguments, Result, ReturnType, Signature, Type,
+};
+
+#[derive(Default)]
+struct ServiceArgs {
+    service: Option<Ident>,
+    error: Option<Path>,
+    app: Option<Ident>,
+}
+
+impl ServiceArgs {
+    fn parse(&mut self, meta: ParseNestedMeta) -> Result<()> {
+        if meta.path.is_ident("service") {
+            self.service = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("error") {
+            self.error = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("app") {
+            self.app = Some(meta.value()?.parse()?);
+        } else {
+            return Err(meta.error("unsupported option, expected `service`, `error` or `app`"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(PartialEq)]
+enum Kind {
+    Query,
+    Mutation,
+}
+
+// Command declared by a method of the subsystem
+struct CommandDef {
+    kind: Kind,
+    name: Ident,
+    id: Option<LitInt>,
+    timeout: Option<LitInt>,
+    out: Option<Type>,
+    retries: Option<LitInt>,
+    backoff: Option<LitInt>,
+    idempotent: bool,
+    func: Ident,
+    asyncness: Option<syn::token::Async>,
+    args: Vec<(Ident, Type)>,
+    reply: Type,
+}
+
+impl CommandDef {
+    // Entry in the grammar of `service_macro!`, or of `app_macro!` with the options of the client
+    fn entry(&self, client: bool) -> TokenStream2 {
+        let CommandDef { name, func, reply, .. } = self;
+        let id = self.id.as_ref().map(|id| quote!(= #id));
+        let names = self.args.iter().map(|(n, _)| n);
+        let types = self.args.iter().map(|(_, t)| t);
+        // the terminal converts the reply of a query into its ground output type
+        let out = self.out.as_ref().unwrap_or(reply);
+        let mut options = self.timeout.as_ref().map(|t| quote!(; timeout: #t)).unwrap_or_default();
+        if client {
+            if let Some(retries) = &self.retries {
+                options.extend(quote!(; retries: #retries));
+            }
+            if let Some(backoff) = &self.backoff {
+                options.extend(quote!(; backoff: #backoff));
+            }
+            if self.idempotent {
+                options.extend(quote!(; idempotent: true));
+            }
+        }
+        match self.kind {
+            Kind::Query => quote! {
+                query: #name #id => fn #func(&self #(, #names: #types)*) -> Result<#reply>; out: #out #options;
+            },
+            Kind::Mutation => quote! {
+                mutation: #name #id => fn #func(&mut self #(, #names: #types)*) -> Result<#reply> #options;
+            },
+        }
+    }
+}
+
+/// Generates the service, terminal and app client from the `impl` block of a subsystem
+///
+/// See the crate documentation for the options.
+#[proc_macro_attribute]
+pub fn cubeos_service(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut args = ServiceArgs::default();
+    let parser = syn::meta::parser(|meta| args.parse(meta));
+    parse_macro_input!(attr with parser);
+    let mut imp = parse_macro_input!(item as ItemImpl);
+    match expand(args, &mut imp) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(args: ServiceArgs, imp: &mut ItemImpl) -> Result<TokenStream2> {
+    if let Some((_, path, _)) = &imp.trait_ {
+        return Err(Error::new_spanned(path, "#[cubeos_service] expects an inherent impl block"));
+    }
+    if !imp.generics.params.is_empty() {
+        return Err(Error::new_spanned(&imp.generics, "#[cubeos_service] does not support generic subsystems"));
+    }
+    let (sub, sub_name) = subsystem_path(&imp.self_ty)?;
+
+    // collect the commands and strip their attributes
+    let mut commands = Vec::new();
+    let mut errors: Option<Error> = None;
+    for item in imp.items.iter_mut() {
+        if let ImplItem::Fn(method) = item {
+            match command(method) {
+                Ok(Some(c)) => commands.push(c),
+                Ok(None) => {}
+                Err(e) => combine(&mut errors, e),
+            }
+        }
+    }
+    let mut names = HashSet::new();
+    for c in commands.iter() {
+        if !names.insert(c.name.to_string()) {
+            combine(&mut errors, Error::new(c.name.span(), format!("duplicate command `{}`", c.name)));
+        }
+    }
+    let mut ids = HashSet::new();
+    for id in commands.iter().filter_map(|c| c.id.as_ref()) {
+        match id.base10_parse::<u16>() {
+            Ok(value) if !ids.insert(value) => combine(&mut errors, Error::new(id.span(), format!("duplicate id `{}`", id))),
+            Ok(_) => {}
+            Err(e) => combine(&mut errors, e),
+        }
+    }
+    // the service calls either all commands with `.await` or none
+    if commands.iter().any(|c| c.asyncness.is_some()) {
+        for c in commands.iter().filter(|c| c.asyncness.is_none()) {
+            combine(&mut errors, Error::new_spanned(&c.func, "commands are either all async or all synchronous"));
+        }
+    }
+    if let Some(e) = errors {
+        return Err(e);
+    }
+
+    let ServiceArgs { service, error, app } = args;
+    let error = error.unwrap_or_else(|| parse_quote!(Error));
+    // the same order for service, terminal and app, so they agree on the CommandIDs
+    let commands: Vec<&CommandDef> = commands
+        .iter()
+        .filter(|c| c.kind == Kind::Query)
+        .chain(commands.iter().filter(|c| c.kind == Kind::Mutation))
+        .collect();
+    let entries: Vec<TokenStream2> = commands.iter().map(|c| c.entry(false)).collect();
+    let is_async = commands.iter().any(|c| c.asyncness.is_some());
+    let backend = if is_async { quote!(async) } else { quote!(udp) };
+    let app = service.map(|service| {
+        let app = app.unwrap_or(sub_name);
+        let entries = commands.iter().map(|c| c.entry(true));
+        let mode = if is_async { quote!(async;) } else { quote!() };
+        quote! {
+            #[cfg(feature = "app")]
+            pub mod app {
+                use super::*;
+                cubeos_service::app_macro! {
+                    #mode
+                    #service: #app {
+                        #(#entries)*
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(quote! {
+        #[cfg(not(feature = "terminal"))]
+        #imp
+
+        #[cfg(not(feature = "terminal"))]
+        pub mod service {
+            use super::*;
+            cubeos_service::service_macro! {
+                #backend;
+                use #error;
+                impl #sub {
+                    #(#entries)*
+                }
+            }
+        }
+
+        #[cfg(feature = "terminal")]
+        pub mod service {
+            use super::*;
+            cubeos_service::service_macro! {
+                terminal;
+                use #error;
+                impl #sub {
+                    #(#entries)*
+                }
+            }
+        }
+
+        #app
+    })
+}
+
+// Path of the subsystem as seen from the generated modules
+fn subsystem_path(ty: &Type) -> Result<(Path, Ident)> {
+    let path = match ty {
+        Type::Path(p) if p.qself.is_none() => &p.path,
+        _ => return Err(Error::new_spanned(ty, "expected the name of the subsystem")),
+    };
+    let last = path.segments.last().unwrap();
+    if !last.arguments.is_empty() {
+        return Err(Error::new_spanned(ty, "#[cubeos_service] does not support generic subsystems"));
+    }
+    let name = last.ident.clone();
+    let first = &path.segments[0].ident;
+    let path = if path.leading_colon.is_some() || first == "crate" {
+        path.clone()
+    } else if first == "self" {
+        let rest = path.segments.iter().skip(1);
+        parse_quote!(super #(::#rest)*)
+    } else {
+        parse_quote!(super::#path)
+    };
+    Ok((path, name))
+}
+
+// Parses a method marked with #[query] or #[mutation]
+fn command(method: &mut ImplItemFn) -> Result<Option<CommandDef>> {
+    let mut marker: Option<(Kind, Attribute)> = None;
+    let mut attrs = Vec::new();
+    for attr in method.attrs.drain(..) {
+        let kind = if attr.path().is_ident("query") {
+            Kind::Query
+        } else if attr.path().is_ident("mutation") {
+            Kind::Mutation
+        } else {
+            attrs.push(attr);
+            continue;
+        };
+        if marker.is_some() {
+            return Err(Error::new_spanned(attr, "a command is either a query or a mutation"));
+        }
+        marker = Some((kind, attr));
+    }
+    method.attrs = attrs;
+    let (kind, attr) = match marker {
+        Some(m) => m,
+        None => return Ok(None),
+    };
+
+    let sig = &method.sig;
+    if !sig.generics.params.is_empty() {
+        return Err(Error::new_spanned(&sig.generics, "commands cannot be generic"));
+    }
+    match sig.receiver() {
+        Some(r) if r.reference.is_none() => {
+            return Err(Error::new_spanned(r, "commands take `&self` or `&mut self`"))
+        }
+        Some(r) if kind == Kind::Query && r.mutability.is_some() => {
+            return Err(Error::new_spanned(r, "queries lock the subsystem for reading and take `&self`"))
+        }
+        Some(_) => {}
+        None => return Err(Error::new_spanned(sig, "commands take `&self` or `&mut self`")),
+    }
+
+    let mut args = Vec::new();
+    for (i, arg) in sig.inputs.iter().skip(1).enumerate() {
+        let arg = match arg {
+            FnArg::Typed(arg) => arg,
+            FnArg::Receiver(r) => return Err(Error::new_spanned(r, "unexpected receiver")),
+        };
+        let name = match &*arg.pat {
+            Pat::Ident(p) if p.subpat.is_none() && p.by_ref.is_none() => p.ident.clone(),
+            Pat::Wild(_) => format_ident!("arg{}", i),
+            pat => return Err(Error::new_spanned(pat, "expected the name of the argument")),
+        };
+        args.push((name, (*arg.ty).clone()));
+    }
+
+    let mut def = CommandDef {
+        kind,
+        name: command_name(&sig.ident),
+        id: None,
+        timeout: None,
+        out: None,
+        retries: None,
+        backoff: None,
+        idempotent: false,
+        func: sig.ident.clone(),
+        asyncness: sig.asyncness,
+        args,
+        reply: reply_type(sig)?,
+    };
+    if let Meta::List(_) = attr.meta {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                def.name = meta.value()?.parse()?;
+            } else if meta.path.is_ident("id") {
+                def.id = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("timeout") {
+                def.timeout = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("out") {
+                if def.kind == Kind::Mutation {
+                    return Err(meta.error("mutations have no ground output type"));
+                }
+                def.out = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("retries") {
+                def.retries = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("backoff") {
+                def.backoff = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("idempotent") {
+                if def.kind == Kind::Query {
+                    return Err(meta.error("queries are always idempotent"));
+                }
+                def.idempotent = true;
+            } else {
+                return Err(meta.error(
+                    "unsupported option, expected `name`, `id`, `timeout`, `out`, `retries`, `backoff` or `idempotent`",
+                ));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(Some(def))
+}
+
+// `T` of a `Result<T>` return type
+fn reply_type(sig: &Signature) -> Result<Type> {
+    let ty = match &sig.output {
+        ReturnType::Type(_, ty) => ty,
+        ReturnType::Default => return Err(Error::new_spanned(&sig.ident, "commands return `Result<T>`")),
+    };
+    if let Type::Path(p) = &**ty {
+        if let Some(last) = p.path.segments.last().filter(|s| s.ident == "Result") {
+            if let PathArguments::AngleBracketed(a) = &last.arguments {
+                if let Some(GenericArgument::Type(t)) = a.args.first() {
+                    return Ok(t.clone());
+                }
+            }
+        }
+    }
+    Err(Error::new_spanned(ty, "commands return `Result<T>`"))
+}
+
+// CommandID of a method, `get_value` becomes `GetValue`
+fn command_name(func: &Ident) -> Ident {
+    let name: String = func
+        .to_string()
+        .trim_start_matches("r#")
+        .split('_')
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            let mut c = s.chars();
+            match c.next() {
+                Some(f) => f.to_uppercase().chain(c).collect::<String>(),
+                None => String::new(),
+            }
+        })
+        .collect();
+    Ident::new(&name, func.span())
+}
+
+fn combine(errors: &mut Option<Error>, e: Error) {
+    match errors {
+        Some(errors) => errors.combine(e),
+        None => *errors = Some(e),
+    }
+}
@@ -0,0 +1,18 @@
+use cubeos_service_macro::cubeos_service;
+
+pub struct Subsystem;
+
+#[cubeos_service(service = example_service)]
+impl Subsystem {
+    #[query(id = 0x0020)]
+    pub fn get_value(&self) -> Result<u8> {
+        Ok(0)
+    }
+
+    #[mutation(id = 32)]
+    pub fn set_value(&mut self, value: u8) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn main() {}
@@ -0,0 +1,13 @@
+use cubeos_service_macro::cubeos_service;
+
+pub struct Subsystem;
+
+#[cubeos_service(service = example_service)]
+impl Subsystem {
+    #[query]
+    pub fn get_value(&self) -> Option<u8> {
+        None
+    }
+}
+
+fn main() {}
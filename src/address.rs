@@ -0,0 +1,98 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Contributed by: Patrick Oppel (patrick.oppel94@gmail.com)
+//
+
+// Addresses of services and their clients
+//
+// The `addr` section of a service's config gives either an IP address and port,
+// or the path of a Unix datagram socket for services and apps on the same computer:
+//
+// ```toml,ignore
+// [service-name.addr]
+// ip = "0.0.0.0"
+// port = 8020
+// protocol = "tcp"       # udp or tcp, default udp
+//
+// [other-service.addr]
+// path = "/run/cubeos/eps.sock"
+// mode = 0o660           # permissions of the socket file, default from umask
+// ```
+
+use crate::error::*;
+use kubos_system::Config;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Sender of a request or local end of a transport
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Address {
+    /// UDP socket
+    Ip(SocketAddr),
+    /// Serial device
+    Serial(String),
+    /// Unix datagram socket
+    Unix(PathBuf),
+    /// TCP connection
+    Tcp(SocketAddr),
+    /// CSP node and port
+    Csp { address: u8, port: u8 },
+}
+
+impl Address {
+    /// Reads the address in the `addr` section of a service's config
+    pub fn from_config(config: &Config) -> Result<Self> {
+        if let Some(path) = config.get("addr").and_then(|a| a.get("path").cloned()) {
+            return match path.as_str() {
+                Some(p) if !p.is_empty() => Ok(Address::Unix(PathBuf::from(p))),
+                _ => Err(Error::InvalidConfig("addr.path".to_string())),
+            };
+        }
+        let hosturl = config
+            .hosturl()
+            .ok_or_else(|| Error::InvalidConfig("addr".to_string()))?;
+        let addr = hosturl
+            .parse::<SocketAddr>()
+            .map_err(|_| Error::InvalidConfig("addr".to_string()))?;
+        match config.get("addr").and_then(|a| a.get("protocol").map(|p| p.as_str().map(str::to_string))) {
+            None => Ok(Address::Ip(addr)),
+            Some(Some(p)) if p == "udp" => Ok(Address::Ip(addr)),
+            Some(Some(p)) if p == "tcp" => Ok(Address::Tcp(addr)),
+            Some(_) => Err(Error::InvalidConfig("addr.protocol".to_string())),
+        }
+    }
+
+    /// Socket address of an IP transport
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Address::Ip(addr) | Address::Tcp(addr) => Some(*addr),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Address::Ip(addr) => write!(f, "{}", addr),
+            Address::Serial(path) => write!(f, "{}", path),
+            Address::Unix(path) => write!(f, "{}", path.display()),
+            Address::Tcp(addr) => write!(f, "tcp://{}", addr),
+            Address::Csp { address, port } => write!(f, "csp://{}:{}", address, port),
+        }
+    }
+}
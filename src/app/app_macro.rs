@@ -1,63 +1,152 @@
+// The entries are parsed one by one into the form
+// `{ kind Type (id) func [msg: Arg, ..] Reply [(option value) ..] }`.
+// The clauses after the signature set the options of the call:
+// `timeout: ms` waits that long plus `DEFAULT_CALL_TIMEOUT` for the reply,
+// `retries: n` and `backoff: ms` send the request again if it fails,
+// `idempotent: bool` allows to send it again without a reply (default for queries).
 #[macro_export]
 macro_rules! app_macro{
+    // `async;` generates `async fn`s using an `AsyncClient`
+    (
+        async; $service: tt: $struct: tt {
+            $($entries: tt)*
+        }
+    ) => {
+        $crate::app_macro!{@parse [$service; $struct; async] [] $($entries)*}
+    };
     (
-        // $app: tt: $timeout: tt;
         $service: tt: $struct: tt {
-            $(            
-                $(query)?$(mutation)?: $type: ident => fn $func: tt (&$(mut )?self $(,$msg: tt: $cmd: ty)*) -> $ign1: tt<$rep: ty> $(; out: $gql_q: ty)?;
-            )*
+            $($entries: tt)*
         }
     ) => {
-        use cubeos_service::udp_rs::Connection;
+        $crate::app_macro!{@parse [$service; $struct; sync] [] $($entries)*}
+    };
+    (@parse $head: tt [$($parsed: tt)*]
+        $kind: ident: $type: ident $(= $id: literal)? => fn $func: tt (&$(mut )?self $(,$msg: tt: $cmd: ty)*) -> $ign1: tt<$rep: ty>;
+        $($rest: tt)*
+    ) => {
+        $crate::app_macro!{@option $head [$($parsed)*] {$kind $type ($($id)?) $func [$($msg: $cmd),*] $rep} [] $($rest)*}
+    };
+    (@option $head: tt $parsed: tt $entry: tt [$($option: tt)*] out: $gql_q: ty; $($rest: tt)*) => {
+        $crate::app_macro!{@option $head $parsed $entry [$($option)*] $($rest)*}
+    };
+    (@option $head: tt $parsed: tt $entry: tt [$($option: tt)*] timeout: $timeout: literal; $($rest: tt)*) => {
+        $crate::app_macro!{@option $head $parsed $entry [$($option)* (timeout Some(std::time::Duration::from_millis($timeout) + cubeos_service::DEFAULT_CALL_TIMEOUT))] $($rest)*}
+    };
+    (@option $head: tt $parsed: tt $entry: tt [$($option: tt)*] retries: $retries: literal; $($rest: tt)*) => {
+        $crate::app_macro!{@option $head $parsed $entry [$($option)* (retries $retries)] $($rest)*}
+    };
+    (@option $head: tt $parsed: tt $entry: tt [$($option: tt)*] backoff: $backoff: literal; $($rest: tt)*) => {
+        $crate::app_macro!{@option $head $parsed $entry [$($option)* (backoff std::time::Duration::from_millis($backoff))] $($rest)*}
+    };
+    (@option $head: tt $parsed: tt $entry: tt [$($option: tt)*] idempotent: $idempotent: literal; $($rest: tt)*) => {
+        $crate::app_macro!{@option $head $parsed $entry [$($option)* (idempotent $idempotent)] $($rest)*}
+    };
+    (@option $head: tt [$($parsed: tt)*] {$($entry: tt)*} $options: tt $($rest: tt)*) => {
+        $crate::app_macro!{@parse $head [$($parsed)* {$($entry)* $options}] $($rest)*}
+    };
+    (@parse [$service: tt; $struct: tt; $mode: tt] [$(
+        {$kind: ident $type: ident ($($id: literal)?) $func: tt [$($msg: tt: $cmd: ty),*] $rep: ty [$(($option: ident $value: expr))*]}
+    )*]) => {
         use cubeos_service::command_id;
         use std::str::FromStr;
         use log::debug;
 
         command_id!{
-            $($type,)*
+            $($type $(= $id)?,)*
         }        
 
-        lazy_static! {
-            static ref HOST_URL: String = {
-                Config::new(&stringify!($service).replace("_","-"))
-                    .unwrap()
-                    .hosturl()
-                    .unwrap()                  
-            };
-            // static ref APP_URL: String = {
-            //     Config::new(&stringify!($app).replace("_","-"))
-            //         .unwrap()
-            //         .hosturl()
-            //         .unwrap()                  
-            // };
+        // hash of the command table, compared with the service before the first command
+        pub const INTERFACE_HASH: u64 = cubeos_service::interface_hash(&[$(
+            cubeos_service::command_hash(stringify!($type), CommandID::$type.id(), &[$(stringify!($cmd)),*], stringify!($rep)),
+        )*]);
+
+        // client settings from the config section of the service
+        fn client_builder() -> CubeOSResult<cubeos_service::ClientBuilder<CommandID>> {
+            cubeos_service::Config::new(&stringify!($service).replace("_","-"))
+                .map_err(|_| CubeOSError::InvalidConfig(stringify!($service).to_string()))
+                .and_then(|config| cubeos_service::Client::builder().config(&config))
+                .map(|builder| builder.interface(INTERFACE_HASH))
+        }
+
+        $crate::app_macro!{@client $mode $struct [$(
+            {$kind $type $func [$($msg: $cmd),*] $rep [$(($option $value))*]}
+        )*]}
+    };
+    (@client sync $struct: tt [$(
+        {$kind: ident $type: ident $func: tt [$($msg: tt: $cmd: ty),*] $rep: ty [$(($option: ident $value: expr))*]}
+    )*]) => {
+        cubeos_service::lazy_static! {
+            // client of the service, built by the first command that succeeds in building it
+            static ref CLIENT: std::sync::Mutex<Option<std::sync::Arc<cubeos_service::Client<CommandID>>>> = std::sync::Mutex::new(None);
+        }
+
+        // client of the service, a failed build is tried again by the next command
+        fn client() -> CubeOSResult<std::sync::Arc<cubeos_service::Client<CommandID>>> {
+            let mut client = CLIENT.lock().map_err(|_| CubeOSError::PoisonedMutex)?;
+            match &*client {
+                Some(c) => Ok(c.clone()),
+                None => {
+                    let c = std::sync::Arc::new(client_builder()?.build()?);
+                    *client = Some(c.clone());
+                    Ok(c)
+                }
+            }
         }
 
         pub struct $struct {}
         impl $struct {
             $(
                 pub fn $func($($msg:$cmd),*) -> Result<$rep> {
-                    let app_url = "0.0.0.0:0".to_string();
-                    // let app_url = APP_URL.to_string();
-                    let connection = Connection::from_path(app_url,HOST_URL.to_string());
-                    let mut command = Command::serialize(CommandID::$type,($($msg),*))?;
-                    // command.insert(0,0);
-                    debug!("Command: {:?}", command);
-                    // match connection.transfer_timeout(command,std::time::Duration::from_secs($timeout)) {
-                    //     Ok(response) => {
-                    //         debug!("Response: {:?}", response);
-                    //         match Command::<CommandID,$rep>::parse(&response) {
-                    //             Ok(c) => Ok(c.data),
-                    //             Err(e) => Err(e.into()),
-                    //         }
-                    //     },
-                    //     Err(e) => Err(e.into()),
-                    // }
-                    match Command::<CommandID,$rep>::parse(&connection.transfer_timeout(command,std::time::Duration::from_secs(1))?) {
-                        Ok(c) => Ok(c.data),
-                        Err(e) => Err(e),
-                    }                
+                    let client = client()?;
+                    let (args, options) = $crate::app_macro!(@request $kind client [$($msg),*] [$(($option $value))*]);
+                    debug!("Command: {:?} {:?}", CommandID::$type, args);
+                    client.call_with(CommandID::$type, &args, &options)
                 }
             )*
-        }       
-    }
+        }
+    };
+    (@client async $struct: tt [$(
+        {$kind: ident $type: ident $func: tt [$($msg: tt: $cmd: ty),*] $rep: ty [$(($option: ident $value: expr))*]}
+    )*]) => {
+        cubeos_service::lazy_static! {
+            // asynchronous client of the service, built by the first command that succeeds in building it
+            static ref CLIENT: std::sync::Mutex<Option<std::sync::Arc<cubeos_service::AsyncClient<CommandID>>>> = std::sync::Mutex::new(None);
+        }
+
+        // client of the service, a failed build is tried again by the next command
+        fn client() -> CubeOSResult<std::sync::Arc<cubeos_service::AsyncClient<CommandID>>> {
+            let mut client = CLIENT.lock().map_err(|_| CubeOSError::PoisonedMutex)?;
+            match &*client {
+                Some(c) => Ok(c.clone()),
+                None => {
+                    let c = std::sync::Arc::new(client_builder()?.build_async()?);
+                    *client = Some(c.clone());
+                    Ok(c)
+                }
+            }
+        }
+
+        pub struct $struct {}
+        impl $struct {
+            $(
+                pub async fn $func($($msg:$cmd),*) -> Result<$rep> {
+                    let client = client()?;
+                    let (args, options) = $crate::app_macro!(@request $kind client [$($msg),*] [$(($option $value))*]);
+                    debug!("Command: {:?} {:?}", CommandID::$type, args);
+                    client.call_with(CommandID::$type, &args, &options).await
+                }
+            )*
+        }
+    };
+    // encoded arguments and options of a call
+    (@request $kind: ident $client: ident [$($msg: ident),*] [$(($option: ident $value: expr))*]) => {{
+        let mut options = $client.options();
+        options.idempotent = $crate::command_kind!($kind) == cubeos_service::CommandKind::Query;
+        $(options.$option = $value;)*
+        // arguments are encoded one after another, like a tuple of any length
+        let mut args: Vec<u8> = Vec::new();
+        $(cubeos_service::bincode::serialize_into(&mut args, &$msg).map_err(CubeOSError::from)?;)*
+        (args, options)
+    }};
 }
@@ -0,0 +1,295 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Contributed by: Patrick Oppel (patrick.oppel94@gmail.com)
+//
+
+// Asynchronous client side of the request/reply exchange with a service
+//
+// Every call binds its own tokio socket, so calls of different tasks run
+// concurrently and a socket is never used by another runtime than the one
+// that created it. Retransmissions of a call are sent from the same socket.
+// Calls to a service on a Unix socket bind a socket file like `Client`.
+// Calls to a service on TCP share one connection like `Client`, it is kept as a
+// std stream between calls and registered with the runtime of the call using it.
+// A call that finds the connection in use by another call opens one of its own.
+
+use crate::address::Address;
+use crate::builtin::INTERFACE_ID;
+use crate::client::{is_closed, retryable, CallOptions, SocketFile, CONNECT_TIMEOUT};
+use crate::codec::Codec;
+use crate::error::*;
+use crate::frame::{next_seq, Frame};
+use crate::framing::{encode_prefixed, take_prefixed};
+use crate::interface::check_interface;
+use log::debug;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket, UnixDatagram};
+
+/// Asynchronous client for the commands of a service
+///
+/// Built with `ClientBuilder::build_async`. The local address given to the builder
+/// should use port 0, as concurrent calls each bind a socket.
+///
+/// ```rust,ignore
+/// let client = Client::<CommandID>::builder()
+///     .target("10.0.0.2:8020".parse()?)
+///     .retries(2)
+///     .build_async()?;
+/// let value: u8 = client.call(CommandID::GetValue, &bincode::serialize(&ExampleEnum::All)?).await?;
+/// ```
+pub struct AsyncClient<C> {
+    bind: SocketAddr,
+    target: Address,
+    codec: Codec,
+    options: CallOptions,
+    interface: Option<u64>,
+    interface_checked: AtomicBool,
+    // connection to a service on TCP and the bytes received on it, while no call uses it
+    connection: Mutex<Option<(std::net::TcpStream, Vec<u8>)>>,
+    id: PhantomData<fn(C)>,
+}
+
+impl<C> AsyncClient<C>
+where
+    u16: TryFrom<C>,
+    Error: From<<u16 as TryFrom<C>>::Error>,
+{
+    pub(crate) fn new(bind: SocketAddr, target: Address, codec: Codec, options: CallOptions, interface: Option<u64>) -> Self {
+        AsyncClient {
+            bind,
+            target,
+            codec,
+            options,
+            interface,
+            interface_checked: AtomicBool::new(false),
+            connection: Mutex::new(None),
+            id: PhantomData,
+        }
+    }
+
+    /// Socket address of the service, if it is reached over UDP
+    pub fn target(&self) -> Option<SocketAddr> {
+        self.target.socket_addr()
+    }
+
+    /// Address of the service
+    pub fn address(&self) -> &Address {
+        &self.target
+    }
+
+    /// Default options of the calls
+    pub fn options(&self) -> CallOptions {
+        self.options
+    }
+
+    /// Sends a command with its encoded arguments and returns the reply
+    ///
+    /// Uses the default options of the client.
+    pub async fn call<R: Serialize + DeserializeOwned>(&self, id: C, args: &[u8]) -> Result<R> {
+        self.call_with(id, args, &self.options).await
+    }
+
+    /// Sends a command with its encoded arguments and returns the reply
+    ///
+    /// Retries like `Client::call_with`. Dropping the future cancels the call.
+    pub async fn call_with<R: Serialize + DeserializeOwned>(&self, id: C, args: &[u8], options: &CallOptions) -> Result<R> {
+        let id = u16::try_from(id)?;
+        let mut socket = match self.take_connection()? {
+            Some(socket) => socket,
+            None => Socket::bind(&self.target, self.bind).await?,
+        };
+        let result = match self.check_interface(&mut socket).await {
+            Ok(()) => request(&mut socket, &self.target, &self.codec, id, args, options).await,
+            Err(e) => Err(e),
+        };
+        self.keep_connection(socket)?;
+        result
+    }
+
+    // Open connection to a service on TCP that no other call uses
+    fn take_connection(&self) -> Result<Option<Socket>> {
+        let connection = self.connection.lock().map_err(|_| Error::PoisonedMutex)?.take();
+        match connection {
+            Some((stream, received)) if !is_closed(&stream) => {
+                stream.set_nonblocking(true)?;
+                Ok(Some(Socket::Tcp(TcpStream::from_std(stream)?, received)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Keeps the connection of a call for the next call, a closed one is
+    // replaced by the next call
+    fn keep_connection(&self, socket: Socket) -> Result<()> {
+        if let Socket::Tcp(stream, received) = socket {
+            let mut connection = self.connection.lock().map_err(|_| Error::PoisonedMutex)?;
+            if connection.is_none() {
+                *connection = Some((stream.into_std()?, received));
+            }
+        }
+        Ok(())
+    }
+
+    // Compares the interface hash with the service before the first call
+    async fn check_interface(&self, socket: &mut Socket) -> Result<()> {
+        if let Some(local) = self.interface {
+            if !self.interface_checked.load(Ordering::SeqCst) {
+                let options = CallOptions { retries: 0, ..self.options };
+                let remote = request(socket, &self.target, &self.codec, INTERFACE_ID, &[], &options).await;
+                check_interface(&self.target, remote, local)?;
+                self.interface_checked.store(true, Ordering::SeqCst);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Socket of a call
+enum Socket {
+    Udp(UdpSocket),
+    // the socket file is removed when the call ends
+    Unix { socket: UnixDatagram, _file: SocketFile },
+    // connection and the bytes received on it
+    Tcp(TcpStream, Vec<u8>),
+}
+
+impl Socket {
+    async fn bind(target: &Address, bind: SocketAddr) -> Result<Self> {
+        match target {
+            Address::Ip(_) => Ok(Socket::Udp(UdpSocket::bind(bind).await?)),
+            Address::Unix(path) => {
+                let file = SocketFile::new()?;
+                let socket = UnixDatagram::bind(&file.0)?;
+                file.share(path)?;
+                Ok(Socket::Unix { socket, _file: file })
+            }
+            Address::Tcp(addr) => {
+                let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+                    .await
+                    .map_err(|_| Error::from(std::io::ErrorKind::TimedOut))??;
+                stream.set_nodelay(true)?;
+                Ok(Socket::Tcp(stream, Vec::new()))
+            }
+            Address::Serial(_) | Address::Csp { .. } => Err(Error::InvalidConfig("target".to_string())),
+        }
+    }
+
+    async fn send_to(&mut self, msg: &[u8], to: &Address) -> Result<()> {
+        match (self, to) {
+            (Socket::Udp(s), Address::Ip(to)) => s.send_to(msg, to).await.map(|_| ()),
+            (Socket::Unix { socket, .. }, Address::Unix(to)) => socket.send_to(msg, to).await.map(|_| ()),
+            (Socket::Tcp(s, _), Address::Tcp(_)) => s.write_all(&encode_prefixed(msg)).await,
+            _ => Err(std::io::ErrorKind::InvalidInput.into()),
+        }
+        .map_err(Error::from)
+    }
+
+    // Cancelling a read of the connection keeps the bytes received so far
+    async fn recv(&mut self) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; u16::MAX as usize];
+        match self {
+            Socket::Udp(s) => {
+                let (n, _) = s.recv_from(&mut buf).await?;
+                buf.truncate(n);
+                Ok(buf)
+            }
+            Socket::Unix { socket, .. } => {
+                let n = socket.recv(&mut buf).await?;
+                buf.truncate(n);
+                Ok(buf)
+            }
+            Socket::Tcp(s, received) => loop {
+                if let Some(msg) = take_prefixed(received)? {
+                    return Ok(msg);
+                }
+                match s.read(&mut buf).await? {
+                    0 => return Err(Error::from(std::io::ErrorKind::UnexpectedEof)),
+                    n => received.extend_from_slice(&buf[..n]),
+                }
+            },
+        }
+    }
+}
+
+// Sends a command and decodes the reply, sending the same request again on retryable errors
+async fn request<R: Serialize + DeserializeOwned>(
+    socket: &mut Socket,
+    to: &Address,
+    codec: &Codec,
+    id: u16,
+    args: &[u8],
+    options: &CallOptions,
+) -> Result<R> {
+    let seq = next_seq();
+    let request = codec.encode(Frame::encode(id, seq, args)?)?;
+    let mut attempt = 0;
+    loop {
+        let reply = exchange(socket, to, codec, &request, seq, options.timeout).await.and_then(|r| {
+            let frame = Frame::parse(&r)?;
+            match frame.is_error() {
+                true => Err(frame.error()),
+                false => frame.data::<R>(),
+            }
+        });
+        match reply {
+            Err(e) if attempt < options.retries && retryable(&e, options.idempotent) => {
+                let delay = options.backoff.saturating_mul(1u32.checked_shl(attempt).unwrap_or(u32::MAX));
+                attempt += 1;
+                debug!("Retry {} of {} after {:?} in {:?}", attempt, options.retries, e, delay);
+                tokio::time::sleep(delay).await;
+            }
+            reply => return reply,
+        }
+    }
+}
+
+// Sends an encoded request and waits for the reply with sequence number `seq`
+async fn exchange(
+    socket: &mut Socket,
+    to: &Address,
+    codec: &Codec,
+    request: &[u8],
+    seq: u16,
+    timeout: Option<Duration>,
+) -> Result<Vec<u8>> {
+    socket.send_to(request, to).await?;
+    match timeout {
+        Some(t) => tokio::time::timeout(t, receive(socket, codec, seq))
+            .await
+            .unwrap_or(Err(Error::NoReply)),
+        None => receive(socket, codec, seq).await,
+    }
+}
+
+// Receives until the reply with sequence number `seq` arrives
+async fn receive(socket: &mut Socket, codec: &Codec, seq: u16) -> Result<Vec<u8>> {
+    loop {
+        let reply = socket.recv().await?;
+        match codec.decode(&reply) {
+            Ok(frame) if Frame::peek_seq(frame) == Some(seq) => return Ok(frame.to_vec()),
+            Ok(frame) => debug!("Drop reply with sequence number {:?}, expected {}", Frame::peek_seq(frame), seq),
+            Err(e) => debug!("Drop reply: {:?}", e),
+        }
+    }
+}
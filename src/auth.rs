@@ -0,0 +1,184 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Contributed by: Patrick Oppel (patrick.oppel94@gmail.com)
+//
+
+// Authentication of Command frames
+//
+// | Command frame | key ID (u8) | counter (u32 BE) | HMAC-SHA256 truncated to 16 bytes |
+//
+// The MAC is calculated over the direction (the Space Packet type, 1 for commands and
+// 0 for replies), the Command frame (header and payload), the key ID and the counter.
+// A signed reply therefore cannot be sent back to the service as a command.
+// The counter protects commands against replay (see replay.rs).
+//
+// ```toml,ignore
+// [service-name]
+// auth_key_id = 1
+// auth_key = "000102030405060708090a0b0c0d0e0f"  # hex encoded, at least 16 bytes
+// ```
+
+use crate::error::*;
+use crate::spacepacket::PacketType;
+use hmac::{Hmac, Mac};
+use kubos_system::Config;
+use sha2::Sha256;
+use std::convert::TryFrom;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Length of the truncated MAC in bytes
+pub const MAC_LEN: usize = 16;
+/// Length of the authentication trailer in bytes
+pub const AUTH_LEN: usize = 1 + 4 + MAC_LEN;
+/// Minimum length of a key in bytes
+pub const MIN_KEY_LEN: usize = 16;
+
+/// Shared secret used to sign and verify Command frames
+#[derive(Clone, PartialEq)]
+pub struct Key {
+    pub id: u8,
+    secret: Vec<u8>,
+}
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // never print the secret
+        write!(f, "Key {{ id: {} }}", self.id)
+    }
+}
+
+impl Key {
+    pub fn new(id: u8, secret: Vec<u8>) -> Result<Key> {
+        if secret.len() < MIN_KEY_LEN {
+            return Err(Error::InvalidConfig("auth_key".to_string()));
+        }
+        Ok(Key { id, secret })
+    }
+
+    /// Reads the key from the service's config section, if present
+    pub fn from_config(config: &Config) -> Result<Option<Key>> {
+        let secret = match config.get("auth_key") {
+            Some(v) => v
+                .as_str()
+                .and_then(from_hex)
+                .ok_or_else(|| Error::InvalidConfig("auth_key".to_string()))?,
+            None => return Ok(None),
+        };
+        let id = config
+            .get("auth_key_id")
+            .and_then(|v| v.as_integer())
+            .and_then(|id| u8::try_from(id).ok())
+            .ok_or_else(|| Error::InvalidConfig("auth_key_id".to_string()))?;
+        Ok(Some(Key::new(id, secret)?))
+    }
+
+    fn mac(&self, packet_type: PacketType, frame: &[u8], counter: u32) -> HmacSha256 {
+        // HMAC accepts keys of any length
+        let mut mac = HmacSha256::new_from_slice(&self.secret).unwrap();
+        mac.update(&[packet_type as u8]);
+        mac.update(frame);
+        mac.update(&[self.id]);
+        mac.update(&counter.to_be_bytes());
+        mac
+    }
+
+    /// Appends key ID, counter and MAC to a Command frame sent as `packet_type`
+    pub fn sign(&self, packet_type: PacketType, frame: Vec<u8>, counter: u32) -> Vec<u8> {
+        let tag = self.mac(packet_type, &frame, counter).finalize().into_bytes();
+        let mut buf = frame;
+        buf.push(self.id);
+        buf.extend_from_slice(&counter.to_be_bytes());
+        buf.extend_from_slice(&tag[..MAC_LEN]);
+        buf
+    }
+
+    /// Verifies the trailer of a Command frame received as `packet_type`
+    /// and returns the frame and its counter
+    ///
+    /// # Errors
+    ///
+    /// `Error::Unauthenticated` if the trailer is missing, the key ID is unknown or the MAC does not match,
+    /// which includes frames signed for the other direction.
+    pub fn verify<'a>(&self, packet_type: PacketType, msg: &'a [u8]) -> Result<(&'a [u8], u32)> {
+        if msg.len() < AUTH_LEN {
+            return Err(Error::Unauthenticated);
+        }
+        let (frame, trailer) = msg.split_at(msg.len() - AUTH_LEN);
+        if trailer[0] != self.id {
+            return Err(Error::Unauthenticated);
+        }
+        let counter = u32::from_be_bytes([trailer[1], trailer[2], trailer[3], trailer[4]]);
+        self.mac(packet_type, frame, counter)
+            .verify_truncated_left(&trailer[5..])
+            .map_err(|_| Error::Unauthenticated)?;
+        Ok((frame, counter))
+    }
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(id: u8, byte: u8) -> Key {
+        Key::new(id, vec![byte; MIN_KEY_LEN]).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let msg = key(1, 7).sign(PacketType::Telecommand, vec![1, 2, 3], 42);
+        assert_eq!(msg.len(), 3 + AUTH_LEN);
+        assert_eq!(key(1, 7).verify(PacketType::Telecommand, &msg), Ok((&[1u8, 2, 3][..], 42)));
+    }
+
+    #[test]
+    fn rejected() {
+        let msg = key(1, 7).sign(PacketType::Telecommand, vec![1, 2, 3], 42);
+        let mut mac = msg.clone();
+        *mac.last_mut().unwrap() ^= 1;
+        assert_eq!(key(1, 7).verify(PacketType::Telecommand, &mac), Err(Error::Unauthenticated));
+        let mut counter = msg.clone();
+        counter[4] ^= 1;
+        assert_eq!(key(1, 7).verify(PacketType::Telecommand, &counter), Err(Error::Unauthenticated));
+        assert_eq!(key(2, 7).verify(PacketType::Telecommand, &msg), Err(Error::Unauthenticated));
+        assert_eq!(key(1, 8).verify(PacketType::Telecommand, &msg), Err(Error::Unauthenticated));
+        assert_eq!(key(1, 7).verify(PacketType::Telecommand, &msg[..AUTH_LEN - 1]), Err(Error::Unauthenticated));
+        assert!(Key::new(1, vec![0; MIN_KEY_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn direction() {
+        let reply = key(1, 7).sign(PacketType::Telemetry, vec![1, 2, 3], 0);
+        assert_eq!(key(1, 7).verify(PacketType::Telecommand, &reply), Err(Error::Unauthenticated));
+        assert!(key(1, 7).verify(PacketType::Telemetry, &reply).is_ok());
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(from_hex("00ff1A"), Some(vec![0x00, 0xFF, 0x1A]));
+        assert_eq!(from_hex("0"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+}
@@ -0,0 +1,149 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Contributed by: Patrick Oppel (patrick.oppel94@gmail.com)
+//
+
+// Built-in commands of every service
+//
+// | CommandID | Command | Reply                     |
+// |-----------|---------|---------------------------|
+// | 0x0001    | Ping    | ()                        |
+// | 0x0002    | LastCmd | last command frame        |
+// | 0x0003    | LastErr | last Error                |
+// | 0x0004    | LastHistory | recent commands and errors |
+// | 0x0005    | Describe | description of all commands (see interface.rs) |
+// | 0x0006    | Interface | hash of the command table (see interface.rs) |
+//
+// CommandIDs below `FIRST_USER_ID` are reserved for built-in commands.
+//
+// The generated `udp_handler` calls the `Ping` and `Last` implementations of the subsystem,
+// if there are any. Otherwise Ping replies `()` and the service keeps the recent commands and errors
+// in the `LastTracker` of its context. A handler called outside of a service records nothing.
+// The choice is made at compile time: the method of `&Builtin<T>` is only found if `T` implements
+// the trait, else method resolution falls back to the default on `Builtin<T>`.
+//
+// Before CommandIDs were reserved, the declared commands were numbered from 1.
+// Ground tools built against an older service must be rebuilt, see CHANGELOG.md.
+
+use crate::error::{Error, Result};
+use crate::last::{current_scope, History, Last};
+use crate::ping::Ping;
+
+/// CommandID of the built-in Ping command
+pub const PING_ID: u16 = 0x0001;
+/// CommandID of the built-in LastCmd command
+pub const LAST_CMD_ID: u16 = 0x0002;
+/// CommandID of the built-in LastErr command
+pub const LAST_ERR_ID: u16 = 0x0003;
+/// CommandID of the built-in LastHistory command
+pub const LAST_HISTORY_ID: u16 = 0x0004;
+/// CommandID of the built-in Describe command
+pub const DESCRIBE_ID: u16 = 0x0005;
+/// CommandID of the built-in Interface command
+pub const INTERFACE_ID: u16 = 0x0006;
+/// CommandID of the first command declared in `service_macro!`
+pub const FIRST_USER_ID: u16 = 0x0010;
+
+/// Number of commands and errors kept for subsystems that do not implement `Last`
+pub const LAST_HISTORY_LEN: usize = 16;
+
+/// Wrapper around the subsystem to dispatch the built-in commands
+pub struct Builtin<'a, T>(pub &'a T);
+
+/// Ping of a subsystem implementing `Ping`
+pub trait PingBuiltin {
+    fn builtin_ping(&self) -> Result<()>;
+}
+impl<'a, T: Ping> PingBuiltin for &Builtin<'a, T> {
+    fn builtin_ping(&self) -> Result<()> {
+        self.0.ping()
+    }
+}
+
+/// Ping of any other subsystem
+pub trait PingDefault {
+    fn builtin_ping(&self) -> Result<()>;
+}
+impl<'a, T> PingDefault for Builtin<'a, T> {
+    fn builtin_ping(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Last command and error of a subsystem implementing `Last`
+pub trait LastBuiltin {
+    fn builtin_set_last_cmd(&self, input: Vec<u8>);
+    fn builtin_get_last_cmd(&self) -> Result<Vec<u8>>;
+    fn builtin_set_last_err(&self, err: Error);
+    fn builtin_get_last_err(&self) -> Result<Error>;
+    fn builtin_get_last_history(&self) -> Result<History>;
+}
+impl<'a, T: Last> LastBuiltin for &Builtin<'a, T> {
+    fn builtin_set_last_cmd(&self, input: Vec<u8>) {
+        self.0.set_last_cmd(input)
+    }
+    fn builtin_get_last_cmd(&self) -> Result<Vec<u8>> {
+        self.0.get_last_cmd()
+    }
+    fn builtin_set_last_err(&self, err: Error) {
+        self.0.set_last_err(err)
+    }
+    fn builtin_get_last_err(&self) -> Result<Error> {
+        self.0.get_last_err()
+    }
+    fn builtin_get_last_history(&self) -> Result<History> {
+        self.0.get_last_history()
+    }
+}
+
+/// Last command and error kept by the service for any other subsystem
+pub trait LastDefault {
+    fn builtin_set_last_cmd(&self, input: Vec<u8>);
+    fn builtin_get_last_cmd(&self) -> Result<Vec<u8>>;
+    fn builtin_set_last_err(&self, err: Error);
+    fn builtin_get_last_err(&self) -> Result<Error>;
+    fn builtin_get_last_history(&self) -> Result<History>;
+}
+impl<'a, T> LastDefault for Builtin<'a, T> {
+    fn builtin_set_last_cmd(&self, input: Vec<u8>) {
+        if let Some(scope) = current_scope() {
+            scope.last.set_last_cmd(input)
+        }
+    }
+    fn builtin_get_last_cmd(&self) -> Result<Vec<u8>> {
+        match current_scope() {
+            Some(scope) => scope.last.get_last_cmd(),
+            None => Ok(Vec::new()),
+        }
+    }
+    fn builtin_set_last_err(&self, err: Error) {
+        if let Some(scope) = current_scope() {
+            scope.last.set_last_err(err)
+        }
+    }
+    fn builtin_get_last_err(&self) -> Result<Error> {
+        match current_scope() {
+            Some(scope) => scope.last.get_last_err(),
+            None => Ok(Error::None),
+        }
+    }
+    fn builtin_get_last_history(&self) -> Result<History> {
+        match current_scope() {
+            Some(scope) => scope.last.get_last_history(),
+            None => Ok(History { commands: Vec::new(), errors: Vec::new() }),
+        }
+    }
+}
@@ -0,0 +1,669 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Contributed by: Patrick Oppel (patrick.oppel94@gmail.com)
+//
+
+// Client side of the request/reply exchange with a service
+//
+// `transfer` sends a single request. `Client` keeps one socket for all calls to a
+// service and retransmits requests that are not answered in time. A retransmission
+// is the same datagram as the original request, so the service answers it from its
+// reply cache instead of executing the command again. The cache does not survive a
+// restart of the service, so commands that are not idempotent are only sent again
+// if the service reported that it was busy.
+//
+// A client of a service on a Unix socket binds a socket file of its own in the
+// temporary directory to receive the replies. Its directory is handed to the group
+// of the service's socket file, so a service running as another user of that group
+// can reply and no one else can reach the client. The file is removed when the
+// client is dropped.
+// A client of a service on TCP connects on its first call and keeps the connection,
+// it connects again on the next call after the connection was closed.
+// A client of a service on CSP binds a free port of a `CspNode` for the replies.
+
+use crate::address::Address;
+use crate::builtin::INTERFACE_ID;
+use crate::codec::Codec;
+use crate::error::*;
+use crate::frame::{next_seq, Frame};
+use crate::framing::{encode_prefixed, take_prefixed};
+use crate::interface::check_interface;
+use kubos_system::Config;
+use log::debug;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+#[cfg(feature = "udp")]
+use std::sync::Arc;
+#[cfg(feature = "udp")]
+use crate::service::{CspNode, CspTransport, Transport};
+use std::time::{Duration, Instant};
+
+/// Timeout of a call if none is configured
+pub const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(1);
+/// Timeout of connecting to a service on TCP
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+// Interval of a CSP client waiting for a reply without timeout
+#[cfg(feature = "udp")]
+const CSP_POLL: Duration = Duration::from_millis(100);
+
+/// Sends the request frame `cmd` to `to` and waits for the matching reply
+///
+/// Replies are matched by the sequence number of the request.
+/// Late replies to earlier requests and messages that fail the Codec checks are dropped.
+/// Returns the reply frame, or `Error::NoReply` if no matching reply arrives within `timeout`.
+/// A `timeout` of `None` waits indefinitely.
+pub fn transfer(
+    socket: &UdpSocket,
+    to: &SocketAddr,
+    codec: &Codec,
+    cmd: Vec<u8>,
+    timeout: Option<Duration>,
+) -> Result<Vec<u8>> {
+    let seq = Frame::peek_seq(&cmd).ok_or(Error::Truncated)?;
+    exchange(socket, &Address::Ip(*to), codec, &codec.encode(cmd)?, seq, timeout)
+}
+
+// Datagram socket of a client
+trait Datagram: Send {
+    fn send_to(&self, msg: &[u8], to: &Address) -> Result<()>;
+    fn recv(&self) -> std::io::Result<Vec<u8>>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()>;
+}
+
+impl Datagram for UdpSocket {
+    fn send_to(&self, msg: &[u8], to: &Address) -> Result<()> {
+        match to {
+            Address::Ip(to) => {
+                UdpSocket::send_to(self, msg, to)?;
+                Ok(())
+            }
+            _ => Err(Error::from(std::io::ErrorKind::InvalidInput)),
+        }
+    }
+
+    fn recv(&self) -> std::io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; u16::MAX as usize];
+        let (n, _) = self.recv_from(&mut buf)?;
+        buf.truncate(n);
+        Ok(buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        Ok(UdpSocket::set_read_timeout(self, timeout)?)
+    }
+}
+
+// Unix datagram socket bound to a socket file of the client
+struct UnixSocket {
+    socket: UnixDatagram,
+    _file: SocketFile,
+}
+
+impl UnixSocket {
+    fn bind(service: &Path) -> Result<Self> {
+        let file = SocketFile::new()?;
+        let socket = UnixDatagram::bind(&file.0)?;
+        file.share(service)?;
+        Ok(UnixSocket { socket, _file: file })
+    }
+}
+
+impl Datagram for UnixSocket {
+    fn send_to(&self, msg: &[u8], to: &Address) -> Result<()> {
+        match to {
+            Address::Unix(path) => {
+                self.socket.send_to(msg, path)?;
+                Ok(())
+            }
+            _ => Err(Error::from(std::io::ErrorKind::InvalidInput)),
+        }
+    }
+
+    fn recv(&self) -> std::io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; u16::MAX as usize];
+        let n = self.socket.recv(&mut buf)?;
+        buf.truncate(n);
+        Ok(buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        Ok(self.socket.set_read_timeout(timeout)?)
+    }
+}
+
+// TCP connection to a service with length-prefixed messages
+//
+// Bytes of a reply received before a read timed out are kept for the next read.
+struct TcpConnection {
+    // stream and the bytes received on it
+    stream: Mutex<Option<(TcpStream, Vec<u8>)>>,
+    timeout: Mutex<Option<Duration>>,
+}
+
+impl TcpConnection {
+    fn new() -> Self {
+        TcpConnection { stream: Mutex::new(None), timeout: Mutex::new(None) }
+    }
+}
+
+impl Datagram for TcpConnection {
+    fn send_to(&self, msg: &[u8], to: &Address) -> Result<()> {
+        let to = match to {
+            Address::Tcp(to) => to,
+            _ => return Err(Error::from(std::io::ErrorKind::InvalidInput)),
+        };
+        let mut stream = self.stream.lock().map_err(|_| Error::PoisonedMutex)?;
+        let (s, _) = match stream.take() {
+            Some(connection) if !is_closed(&connection.0) => stream.insert(connection),
+            _ => {
+                let s = TcpStream::connect_timeout(to, CONNECT_TIMEOUT)?;
+                s.set_nodelay(true)?;
+                debug!("Connected to {}", to);
+                stream.insert((s, Vec::new()))
+            }
+        };
+        if let Err(e) = s.write_all(&encode_prefixed(msg)) {
+            // connect again on the next call
+            *stream = None;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    fn recv(&self) -> std::io::Result<Vec<u8>> {
+        let mut stream = self
+            .stream
+            .lock()
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "poisoned mutex"))?;
+        let timeout = self.timeout.lock().map(|t| *t).unwrap_or(None);
+        let result = match stream.as_mut() {
+            Some((s, buf)) => read_reply(s, buf, timeout),
+            None => Err(std::io::ErrorKind::NotConnected.into()),
+        };
+        match &result {
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => {}
+            Err(_) => *stream = None,
+            Ok(_) => {}
+        }
+        result
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        *self.timeout.lock().map_err(|_| Error::PoisonedMutex)? = timeout;
+        Ok(())
+    }
+}
+
+// Whether the service closed the connection, e.g. after it was idle
+pub(crate) fn is_closed(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return true;
+    }
+    let result = stream.peek(&mut [0u8; 1]);
+    if stream.set_nonblocking(false).is_err() {
+        return true;
+    }
+    match result {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => e.kind() != std::io::ErrorKind::WouldBlock,
+    }
+}
+
+// Port of a CSP node, reads wait for the packets to the port
+#[cfg(feature = "udp")]
+struct CspSocket {
+    transport: CspTransport,
+    timeout: Mutex<Option<Duration>>,
+}
+
+#[cfg(feature = "udp")]
+impl Datagram for CspSocket {
+    fn send_to(&self, msg: &[u8], to: &Address) -> Result<()> {
+        self.transport.send(msg, to)
+    }
+
+    fn recv(&self) -> std::io::Result<Vec<u8>> {
+        let timeout = self.timeout.lock().map(|t| *t).unwrap_or(None);
+        loop {
+            match self.transport.recv(timeout.unwrap_or(CSP_POLL)) {
+                Ok(Some((msg, _))) => return Ok(msg),
+                Ok(None) if timeout.is_some() => return Err(std::io::ErrorKind::WouldBlock.into()),
+                Ok(None) => {}
+                Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("{:?}", e))),
+            }
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        *self.timeout.lock().map_err(|_| Error::PoisonedMutex)? = timeout;
+        Ok(())
+    }
+}
+
+// Reads from the stream until `buf` holds a complete message
+fn read_reply(stream: &mut TcpStream, buf: &mut Vec<u8>, timeout: Option<Duration>) -> std::io::Result<Vec<u8>> {
+    stream.set_read_timeout(timeout)?;
+    let mut chunk = [0u8; 4096];
+    loop {
+        if let Some(msg) = take_prefixed(buf)? {
+            return Ok(msg);
+        }
+        match stream.read(&mut chunk)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            n => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+}
+
+// Socket file of a client, removed on drop
+//
+// The file is bound in a directory of the temporary directory that only the
+// user of the client can access, `share` then opens it to the service.
+pub(crate) struct SocketFile(pub(crate) PathBuf);
+
+impl SocketFile {
+    pub(crate) fn new() -> Result<Self> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("cubeos-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::SeqCst));
+        let dir = std::env::temp_dir().join(name);
+        // left behind by a process with the same pid
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+        Ok(SocketFile(dir.join("reply.sock")))
+    }
+
+    // Lets the group of the socket file `service` send to the bound socket file
+    //
+    // If the directory cannot be handed to that group, e.g. as the client is not a
+    // member of it, it stays private and only services of the same user can reply.
+    pub(crate) fn share(&self, service: &Path) -> Result<()> {
+        let dir = match self.0.parent() {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let gid = match std::fs::metadata(service) {
+            Ok(meta) => meta.gid(),
+            Err(e) => {
+                debug!("Keep {} private, {}: {}", dir.display(), service.display(), e);
+                return Ok(());
+            }
+        };
+        let c_dir = CString::new(dir.as_os_str().as_bytes()).map_err(|_| Error::from(std::io::ErrorKind::InvalidInput))?;
+        if unsafe { libc::chown(c_dir.as_ptr(), libc::uid_t::MAX, gid) } != 0 {
+            debug!("Keep {} private: {}", dir.display(), std::io::Error::last_os_error());
+            return Ok(());
+        }
+        std::fs::set_permissions(&self.0, std::fs::Permissions::from_mode(0o660))?;
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o710))?;
+        Ok(())
+    }
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+        if let Some(dir) = self.0.parent() {
+            let _ = std::fs::remove_dir(dir);
+        }
+    }
+}
+
+// Sends an encoded request and waits for the reply with sequence number `seq`
+fn exchange(
+    socket: &dyn Datagram,
+    to: &Address,
+    codec: &Codec,
+    request: &[u8],
+    seq: u16,
+    timeout: Option<Duration>,
+) -> Result<Vec<u8>> {
+    let deadline = timeout.map(|t| Instant::now() + t);
+    if deadline.is_none() {
+        socket.set_read_timeout(None)?;
+    }
+    socket.send_to(request, to)?;
+
+    loop {
+        if let Some(deadline) = deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                return Err(Error::NoReply);
+            }
+            socket.set_read_timeout(Some(remaining))?;
+        }
+        let reply = match socket.recv() {
+            Ok(reply) => reply,
+            // the read timeout expired, checked against the deadline above
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e.into()),
+        };
+        match codec.decode(&reply) {
+            Ok(frame) if Frame::peek_seq(frame) == Some(seq) => return Ok(frame.to_vec()),
+            Ok(frame) => debug!("Drop reply with sequence number {:?}, expected {}", Frame::peek_seq(frame), seq),
+            Err(e) => debug!("Drop reply: {:?}", e),
+        }
+    }
+}
+
+// Errors after which the unchanged request may be sent again
+//
+// A busy service has not executed the command. A missing reply may belong to a command
+// that was executed, so only idempotent commands are sent again.
+pub(crate) fn retryable(e: &Error, idempotent: bool) -> bool {
+    match e {
+        Error::Busy => true,
+        Error::NoReply => idempotent,
+        _ => false,
+    }
+}
+
+/// Timeout and retries of a call
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CallOptions {
+    /// Time to wait for the reply to each request, `None` waits indefinitely
+    pub timeout: Option<Duration>,
+    /// Number of times the request is sent again
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every further retry
+    pub backoff: Duration,
+    /// Whether the request is also sent again if there is no reply,
+    /// otherwise it is only sent again if the service was busy.
+    /// Not set by default, as a lost reply may belong to a command that was executed.
+    pub idempotent: bool,
+}
+
+impl Default for CallOptions {
+    fn default() -> Self {
+        CallOptions {
+            timeout: Some(DEFAULT_CALL_TIMEOUT),
+            retries: 0,
+            backoff: Duration::from_millis(0),
+            idempotent: false,
+        }
+    }
+}
+
+/// Client for the commands of a service
+///
+/// All calls share one socket and are sent one at a time.
+///
+/// ```rust,ignore
+/// let client = Client::<CommandID>::builder()
+///     .target("10.0.0.2:8020".parse()?)
+///     .timeout(Some(Duration::from_secs(5)))
+///     .retries(2)
+///     .backoff(Duration::from_millis(100))
+///     .build()?;
+/// let value: u8 = client.call(CommandID::GetValue, &bincode::serialize(&ExampleEnum::All)?)?;
+/// ```
+pub struct Client<C> {
+    socket: Mutex<Box<dyn Datagram>>,
+    target: Address,
+    codec: Codec,
+    options: CallOptions,
+    interface: Option<u64>,
+    interface_checked: AtomicBool,
+    id: PhantomData<fn(C)>,
+}
+
+impl<C> Client<C>
+where
+    u16: TryFrom<C>,
+    Error: From<<u16 as TryFrom<C>>::Error>,
+{
+    pub fn builder() -> ClientBuilder<C> {
+        ClientBuilder::default()
+    }
+
+    /// Socket address of the service, if it is reached over UDP
+    pub fn target(&self) -> Option<SocketAddr> {
+        self.target.socket_addr()
+    }
+
+    /// Address of the service
+    pub fn address(&self) -> &Address {
+        &self.target
+    }
+
+    /// Default options of the calls
+    pub fn options(&self) -> CallOptions {
+        self.options
+    }
+
+    /// Sends a command with its encoded arguments and returns the reply
+    ///
+    /// Uses the default options of the client.
+    pub fn call<R: Serialize + DeserializeOwned>(&self, id: C, args: &[u8]) -> Result<R> {
+        self.call_with(id, args, &self.options)
+    }
+
+    /// Sends a command with its encoded arguments and returns the reply
+    ///
+    /// The request is sent again up to `options.retries` times if the service is busy,
+    /// or if the call is idempotent and there is no reply within `options.timeout`.
+    /// Returns `Error::NoReply` if the last request is not answered in time.
+    pub fn call_with<R: Serialize + DeserializeOwned>(&self, id: C, args: &[u8], options: &CallOptions) -> Result<R> {
+        let id = u16::try_from(id)?;
+        let socket = self.socket.lock().map_err(|_| Error::PoisonedMutex)?;
+        self.check_interface(&**socket)?;
+        request(&**socket, &self.target, &self.codec, id, args, options)
+    }
+
+    // Compares the interface hash with the service before the first call
+    fn check_interface(&self, socket: &dyn Datagram) -> Result<()> {
+        if let Some(local) = self.interface {
+            if !self.interface_checked.load(Ordering::SeqCst) {
+                let options = CallOptions { retries: 0, ..self.options };
+                let remote = request(socket, &self.target, &self.codec, INTERFACE_ID, &[], &options);
+                check_interface(&self.target, remote, local)?;
+                self.interface_checked.store(true, Ordering::SeqCst);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Sends a command and decodes the reply, sending the same request again on retryable errors
+fn request<R: Serialize + DeserializeOwned>(
+    socket: &dyn Datagram,
+    to: &Address,
+    codec: &Codec,
+    id: u16,
+    args: &[u8],
+    options: &CallOptions,
+) -> Result<R> {
+    let seq = next_seq();
+    let request = codec.encode(Frame::encode(id, seq, args)?)?;
+    let mut attempt = 0;
+    loop {
+        let reply = exchange(socket, to, codec, &request, seq, options.timeout).and_then(|r| {
+            let frame = Frame::parse(&r)?;
+            match frame.is_error() {
+                true => Err(frame.error()),
+                false => frame.data::<R>(),
+            }
+        });
+        match reply {
+            Err(e) if attempt < options.retries && retryable(&e, options.idempotent) => {
+                let delay = options.backoff.saturating_mul(1u32.checked_shl(attempt).unwrap_or(u32::MAX));
+                attempt += 1;
+                debug!("Retry {} of {} after {:?} in {:?}", attempt, options.retries, e, delay);
+                std::thread::sleep(delay);
+            }
+            reply => return reply,
+        }
+    }
+}
+
+/// Builder of a `Client`
+pub struct ClientBuilder<C> {
+    bind: SocketAddr,
+    target: Option<Address>,
+    #[cfg(feature = "udp")]
+    csp: Option<Arc<CspNode>>,
+    codec: Codec,
+    options: CallOptions,
+    interface: Option<u64>,
+    id: PhantomData<fn(C)>,
+}
+
+impl<C> Default for ClientBuilder<C> {
+    fn default() -> Self {
+        ClientBuilder {
+            bind: SocketAddr::from(([0, 0, 0, 0], 0)),
+            target: None,
+            #[cfg(feature = "udp")]
+            csp: None,
+            codec: Codec::default(),
+            options: CallOptions::default(),
+            interface: None,
+            id: PhantomData,
+        }
+    }
+}
+
+impl<C> ClientBuilder<C> {
+    /// Local address of the UDP socket, any free port by default
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.bind = addr;
+        self
+    }
+
+    /// Address of the service
+    pub fn target(mut self, addr: SocketAddr) -> Self {
+        self.target = Some(Address::Ip(addr));
+        self
+    }
+
+    /// Path of the Unix socket of a service on the same computer
+    pub fn target_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.target = Some(Address::Unix(path.into()));
+        self
+    }
+
+    /// Address of a service on TCP
+    pub fn target_tcp(mut self, addr: SocketAddr) -> Self {
+        self.target = Some(Address::Tcp(addr));
+        self
+    }
+
+    /// Port of a service at CSP `address`, reached through `node`
+    #[cfg(feature = "udp")]
+    pub fn target_csp(mut self, node: &Arc<CspNode>, address: u8, port: u8) -> Self {
+        self.target = Some(Address::Csp { address, port });
+        self.csp = Some(node.clone());
+        self
+    }
+
+    /// Reads the address and Codec of the service from its config section
+    pub fn config(mut self, config: &Config) -> Result<Self> {
+        self.target = Some(Address::from_config(config)?);
+        self.codec = Codec::from_config(config)?;
+        Ok(self)
+    }
+
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Default options of a call, replaces the options set before
+    pub fn options(mut self, options: CallOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Default timeout of a call, 1 s if not set
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.options.timeout = timeout;
+        self
+    }
+
+    /// Default number of retries of a call, none if not set
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.options.retries = retries;
+        self
+    }
+
+    /// Default delay before the first retry of a call, no delay if not set
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.options.backoff = backoff;
+        self
+    }
+
+    /// Whether calls are sent again if there is no reply, not set by default
+    pub fn idempotent(mut self, idempotent: bool) -> Self {
+        self.options.idempotent = idempotent;
+        self
+    }
+
+    /// Interface hash the service has to report before the first call
+    pub fn interface(mut self, hash: u64) -> Self {
+        self.interface = Some(hash);
+        self
+    }
+
+    /// Builds an `AsyncClient` with the same settings
+    #[cfg(feature = "async")]
+    pub fn build_async(self) -> Result<crate::async_client::AsyncClient<C>>
+    where
+        u16: TryFrom<C>,
+        Error: From<<u16 as TryFrom<C>>::Error>,
+    {
+        let target = self
+            .target
+            .ok_or_else(|| Error::InvalidConfig("target".to_string()))?;
+        Ok(crate::async_client::AsyncClient::new(self.bind, target, self.codec, self.options, self.interface))
+    }
+
+    pub fn build(self) -> Result<Client<C>> {
+        let target = self
+            .target
+            .ok_or_else(|| Error::InvalidConfig("target".to_string()))?;
+        let socket: Box<dyn Datagram> = match &target {
+            Address::Ip(_) => Box::new(UdpSocket::bind(self.bind)?),
+            Address::Unix(path) => Box::new(UnixSocket::bind(path)?),
+            Address::Tcp(_) => Box::new(TcpConnection::new()),
+            #[cfg(feature = "udp")]
+            Address::Csp { .. } => match &self.csp {
+                Some(node) => Box::new(CspSocket { transport: node.bind_any()?, timeout: Mutex::new(None) }),
+                None => return Err(Error::InvalidConfig("target".to_string())),
+            },
+            _ => return Err(Error::InvalidConfig("target".to_string())),
+        };
+        Ok(Client {
+            socket: Mutex::new(socket),
+            target,
+            codec: self.codec,
+            options: self.options,
+            interface: self.interface,
+            interface_checked: AtomicBool::new(false),
+            id: PhantomData,
+        })
+    }
+}
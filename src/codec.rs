@@ -0,0 +1,378 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Contributed by: Patrick Oppel (patrick.oppel94@gmail.com)
+//
+
+// Link layer options applied around a Command frame
+//
+// The Codec is configured per service in the `[service-name]` section
+// of the config file. Service and clients read the same section,
+// so both ends of a link always agree on the wire format.
+//
+// ```toml,ignore
+// [service-name]
+// checksum = "crc16"      # "none" (default), "crc16" or "crc32"
+// format = "spacepacket"  # "raw" (default) or "spacepacket"
+// apid = 100              # APID of the service, required for "spacepacket"
+// timestamp = true        # add the timestamp secondary header to Space Packets
+// ```
+//
+// Authentication of frames is configured with `auth_key` and `auth_key_id` (see auth.rs),
+// replay protection with `auth_counter`, `replay_state` and `replay_window` (see replay.rs).
+
+use crate::auth::Key;
+use crate::error::*;
+use crate::frame::Frame;
+use crate::replay::{Counter,ReplayWindow};
+use crate::spacepacket::{PacketType,SpacePacket,Timestamp,MAX_APID};
+use kubos_system::Config;
+use std::sync::Arc;
+
+/// Checksum trailer appended to every frame
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Checksum {
+    /// No trailer
+    #[default]
+    None,
+    /// CRC-16-CCITT (poly 0x1021, init 0xFFFF), 2 byte trailer
+    Crc16,
+    /// CRC-32 (IEEE 802.3), 4 byte trailer
+    Crc32,
+}
+impl Checksum {
+    /// Length of the trailer in bytes
+    pub fn trailer_len(&self) -> usize {
+        match self {
+            Checksum::None => 0,
+            Checksum::Crc16 => 2,
+            Checksum::Crc32 => 4,
+        }
+    }
+
+    fn trailer(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Checksum::None => Vec::new(),
+            Checksum::Crc16 => crc16_ccitt(data).to_be_bytes().to_vec(),
+            Checksum::Crc32 => crc32(data).to_be_bytes().to_vec(),
+        }
+    }
+}
+impl std::str::FromStr for Checksum {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Checksum::None),
+            "crc16" => Ok(Checksum::Crc16),
+            "crc32" => Ok(Checksum::Crc32),
+            _ => Err(Error::InvalidConfig(format!("checksum = {}", s))),
+        }
+    }
+}
+
+/// Outer format of a message
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Format {
+    /// Command frame only
+    #[default]
+    Raw,
+    /// Command frame as user data of a CCSDS Space Packet
+    SpacePacket,
+}
+impl std::str::FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "raw" => Ok(Format::Raw),
+            "spacepacket" => Ok(Format::SpacePacket),
+            _ => Err(Error::InvalidConfig(format!("format = {}", s))),
+        }
+    }
+}
+
+/// Wire format options of a service
+#[derive(Clone, Debug, Default)]
+pub struct Codec {
+    /// Checksum trailer, checked before the frame is decoded
+    pub checksum: Checksum,
+    /// Outer format
+    pub format: Format,
+    /// APID used in Space Packets
+    pub apid: u16,
+    /// Add a timestamp secondary header to Space Packets
+    pub timestamp: bool,
+    /// Sign and verify frames with this key
+    pub auth: Option<Key>,
+    /// Counter used to sign commands
+    pub counter: Arc<Counter>,
+    /// Last accepted counters of received commands
+    pub replay: Arc<ReplayWindow>,
+}
+
+impl Codec {
+    /// Reads the codec options from the service's config section
+    pub fn from_config(config: &Config) -> Result<Codec> {
+        let checksum = match config.get("checksum") {
+            Some(v) => v
+                .as_str()
+                .ok_or_else(|| Error::InvalidConfig("checksum".to_string()))?
+                .parse::<Checksum>()?,
+            None => Checksum::None,
+        };
+        let format = match config.get("format") {
+            Some(v) => v
+                .as_str()
+                .ok_or_else(|| Error::InvalidConfig("format".to_string()))?
+                .parse::<Format>()?,
+            None => Format::Raw,
+        };
+        let apid = match (format, config.get("apid").and_then(|v| v.as_integer())) {
+            (_, Some(apid)) if (0..=MAX_APID as i64).contains(&apid) => apid as u16,
+            (Format::Raw, None) => 0,
+            _ => return Err(Error::InvalidConfig("apid".to_string())),
+        };
+        let timestamp = config
+            .get("timestamp")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        let auth = Key::from_config(config)?;
+        let (counter, replay) = match auth {
+            Some(_) => (Counter::from_config(config)?, ReplayWindow::from_config(config)?),
+            None => (Counter::default(), ReplayWindow::default()),
+        };
+        Ok(Codec {
+            checksum,
+            format,
+            apid,
+            timestamp,
+            auth,
+            counter: Arc::new(counter),
+            replay: Arc::new(replay),
+        })
+    }
+
+    /// Wraps a request frame for transmission
+    pub fn encode(&self, frame: Vec<u8>) -> Result<Vec<u8>> {
+        self.wrap(PacketType::Telecommand, frame)
+    }
+
+    /// Wraps a reply frame for transmission
+    pub fn encode_reply(&self, frame: Vec<u8>) -> Result<Vec<u8>> {
+        self.wrap(PacketType::Telemetry, frame)
+    }
+
+    fn wrap(&self, packet_type: PacketType, frame: Vec<u8>) -> Result<Vec<u8>> {
+        // replies are bound to their command by the sequence number
+        // and not subject to replay protection
+        let frame = match (&self.auth, packet_type) {
+            (Some(key), PacketType::Telecommand) => key.sign(packet_type, frame, self.counter.next()?),
+            (Some(key), PacketType::Telemetry) => key.sign(packet_type, frame, 0),
+            (None, _) => frame,
+        };
+        let mut buf = match self.format {
+            Format::Raw => frame,
+            Format::SpacePacket => {
+                let timestamp = match self.timestamp {
+                    true => Some(Timestamp::now()),
+                    false => None,
+                };
+                SpacePacket::encode(packet_type, self.apid, timestamp, &frame, self.checksum.trailer_len())?
+            }
+        };
+        let mut trailer = self.checksum.trailer(&buf);
+        buf.append(&mut trailer);
+        Ok(buf)
+    }
+
+    /// Verifies a received reply and returns the contained Command frame
+    ///
+    /// # Errors
+    ///
+    /// `Error::Truncated` if `msg` is shorter than the trailer,
+    /// `Error::Checksum` if the trailer does not match,
+    /// `Error::InvalidPacket` if the Space Packet header is invalid, addressed to another APID
+    /// or not a Telemetry packet,
+    /// `Error::Unauthenticated` if the frame is not signed with the configured key.
+    pub fn decode<'a>(&self, msg: &'a [u8]) -> Result<&'a [u8]> {
+        self.unwrap(msg, PacketType::Telemetry).map(|(frame, _)| frame)
+    }
+
+    /// Verifies a received command like `decode`, without checking the replay window
+    ///
+    /// Space Packets have to be Telecommand packets.
+    pub fn decode_command<'a>(&self, msg: &'a [u8]) -> Result<&'a [u8]> {
+        self.unwrap(msg, PacketType::Telecommand).map(|(frame, _)| frame)
+    }
+
+    /// Verifies a received command and returns the contained Command frame
+    ///
+    /// In addition to `decode_command` the counter of an authenticated command is checked
+    /// against the replay window. Returns `Error::Replay` if it is outside the window.
+    pub fn decode_request<'a>(&self, msg: &'a [u8]) -> Result<&'a [u8]> {
+        let (frame, counter) = self.unwrap(msg, PacketType::Telecommand)?;
+        if let (Some(key), Some(counter)) = (&self.auth, counter) {
+            self.replay.check(key.id, counter)?;
+        }
+        Ok(frame)
+    }
+
+    /// Returns the sequence number of a received command without verifying it,
+    /// used to address the error reply to a command that fails `decode_request`
+    pub fn peek_seq(&self, msg: &[u8]) -> Option<u16> {
+        match self.format {
+            Format::Raw => Frame::peek_seq(msg),
+            Format::SpacePacket => SpacePacket::parse(msg, self.checksum.trailer_len())
+                .ok()
+                .and_then(|packet| Frame::peek_seq(packet.data)),
+        }
+    }
+
+    fn unwrap<'a>(&self, msg: &'a [u8], packet_type: PacketType) -> Result<(&'a [u8], Option<u32>)> {
+        let n = self.checksum.trailer_len();
+        if msg.len() < n {
+            return Err(Error::Truncated);
+        }
+        let (frame, trailer) = msg.split_at(msg.len() - n);
+        if self.checksum.trailer(frame) != trailer {
+            return Err(Error::Checksum);
+        }
+        let frame = match self.format {
+            Format::Raw => frame,
+            Format::SpacePacket => {
+                let packet = SpacePacket::parse(msg, n)?;
+                if packet.header.apid != self.apid || packet.header.packet_type != packet_type {
+                    return Err(Error::InvalidPacket);
+                }
+                packet.data
+            }
+        };
+        match &self.auth {
+            Some(key) => key.verify(packet_type, frame).map(|(f, c)| (f, Some(c))),
+            None => Ok((frame, None)),
+        }
+    }
+}
+
+/// CRC-16-CCITT (poly 0x1021, init 0xFFFF, no reflection)
+pub fn crc16_ccitt(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for b in data {
+        crc ^= (*b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// CRC-32 (IEEE 802.3, reflected poly 0xEDB88320)
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFF_FFFF;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// CRC-32C (Castagnoli, reflected poly 0x82F63B78), the checksum of CSP
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFF_FFFF;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_values() {
+        assert_eq!(crc16_ccitt(b"123456789"), 0x29B1);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+    }
+
+    #[test]
+    fn trailer() {
+        for checksum in [Checksum::Crc16, Checksum::Crc32] {
+            let codec = Codec { checksum, ..Codec::default() };
+            let mut msg = codec.encode(vec![1, 2, 3]).unwrap();
+            assert_eq!(msg.len(), 3 + checksum.trailer_len());
+            assert_eq!(codec.decode(&msg).unwrap(), &[1, 2, 3]);
+            msg[1] ^= 0x10;
+            assert_eq!(codec.decode(&msg), Err(Error::Checksum));
+            assert_eq!(codec.decode(&[0]), Err(Error::Truncated));
+        }
+    }
+
+    #[test]
+    fn space_packet() {
+        let codec = Codec { format: Format::SpacePacket, apid: 100, checksum: Checksum::Crc16, ..Codec::default() };
+        let request = codec.encode(vec![1, 2, 3]).unwrap();
+        assert_eq!(codec.decode_request(&request).unwrap(), &[1, 2, 3]);
+        // a reply is not accepted as a command and vice versa
+        let reply = codec.encode_reply(vec![1, 2, 3]).unwrap();
+        assert_eq!(codec.decode_request(&reply), Err(Error::InvalidPacket));
+        assert_eq!(codec.decode(&request), Err(Error::InvalidPacket));
+        assert_eq!(codec.decode(&reply).unwrap(), &[1, 2, 3]);
+        let other = Codec { apid: 101, ..codec.clone() };
+        assert_eq!(other.decode_request(&request), Err(Error::InvalidPacket));
+    }
+
+    #[test]
+    fn signed_reply_is_not_a_command() {
+        let codec = Codec { auth: Some(Key::new(1, vec![7; 16]).unwrap()), ..Codec::default() };
+        let request = codec.encode(vec![1, 2, 3]).unwrap();
+        assert_eq!(codec.decode_request(&request).unwrap(), &[1, 2, 3]);
+        let reply = codec.encode_reply(vec![1, 2, 3]).unwrap();
+        assert_eq!(codec.decode(&reply).unwrap(), &[1, 2, 3]);
+        assert_eq!(codec.decode_request(&reply), Err(Error::Unauthenticated));
+    }
+
+    #[test]
+    fn seq_of_rejected_command() {
+        let frame = Frame::encode(7, 0x1234, &[1, 2, 3]).unwrap();
+        for format in [Format::Raw, Format::SpacePacket] {
+            let codec = Codec { format, checksum: Checksum::Crc16, ..Codec::default() };
+            let mut request = codec.encode(frame.clone()).unwrap();
+            *request.last_mut().unwrap() ^= 1;
+            assert_eq!(codec.decode_request(&request), Err(Error::Checksum));
+            assert_eq!(codec.peek_seq(&request), Some(0x1234));
+        }
+        assert_eq!(Codec::default().peek_seq(&[0, 7, 0]), None);
+    }
+}
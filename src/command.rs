@@ -17,20 +17,22 @@
 // 
 
 use crate::error::*;
+use crate::frame::{Frame,next_seq};
+use crate::codec::Codec;
 use serde::{Serialize,Deserialize};
 use std::convert::TryFrom;
 
 // Struct that enables deserializing of incoming Vec<u8> msgs
 // into data structures specified in the API or Service
-#[cfg(not(feature = "terminal"))]
 #[derive(Serialize,Deserialize, Debug)]
 pub struct Command<C,T> {
     // SpacePacket Command-ID retained for future use
     pub id: C,
+    // Sequence number, echoed in the reply
+    pub seq: u16,
     // Data from Vec<u8>,
     pub data: T,
 }
-#[cfg(not(feature = "terminal"))]
 impl<'a,C: TryFrom<u16> + Clone, T: Serialize + Deserialize<'a>> Command<C,T>
     where 
         u16: TryFrom<C>,
@@ -47,65 +49,135 @@ impl<'a,C: TryFrom<u16> + Clone, T: Serialize + Deserialize<'a>> Command<C,T>
     pub fn new(id: C, data: T) -> Command<C,T> {
         Command {
             id,
+            seq: next_seq(),
             data,
         }
     }
 
     // parser function
-    pub fn parse(msg: &'a Vec<u8>) -> Result<Self> {       
-        
-        match u16::from_be_bytes([msg[0],msg[1]]) {
-            0 => Err(Error::from(bincode::deserialize::<Error>(&msg[2..])?)),
-            65535 => Err(Error::from(bincode::deserialize::<Error>(&msg[2..])?)),
-            id => Ok(Command{id: C::try_from(id)?,data: bincode::deserialize::<T>(&msg[2..])?}),            
-        }
+    pub fn parse(msg: &'a Vec<u8>) -> Result<Self> {
+        Self::parse_with(msg, &Codec::default())
     }
 
     pub fn ser(&self) -> Result<Vec<u8>> {
-        let mut buf: Vec<u8> = Vec::new();
         let id = self.id.clone();
-
-        buf.append(&mut u16::try_from(id)?.to_be_bytes().to_vec());
-        buf.append(&mut bincode::serialize(&self.data)?);
-        Ok(buf)
+        Frame::encode(u16::try_from(id)?, self.seq, &bincode::serialize(&self.data)?)
     }    
 
     // serializer function
     pub fn serialize(id: C, msg: T) -> Result<Vec<u8>> {
-        let mut buf: Vec<u8> = Vec::new();
+        Command::new(id, msg).ser()
+    }
+
+    // parser function for messages wrapped by a Codec
+    pub fn parse_with(msg: &'a [u8], codec: &Codec) -> Result<Self> {
+        let frame = Frame::parse(codec.decode(msg)?)?;
+        if frame.is_error() {
+            return Err(frame.error());
+        }
+        Ok(Command{id: C::try_from(frame.id)?, seq: frame.seq, data: frame.data::<T>()?})
+    }
+
+    pub fn ser_with(&self, codec: &Codec) -> Result<Vec<u8>> {
+        codec.encode(self.ser()?)
+    }
+
+    // serializer function for messages wrapped by a Codec
+    pub fn serialize_with(id: C, msg: T, codec: &Codec) -> Result<Vec<u8>> {
+        codec.encode(Self::serialize(id, msg)?)
+    }
+}
+
+/// Returns true if a CommandID occurs more than once
+///
+/// Used by `command_id!` to reject duplicate IDs at compile time.
+pub const fn has_duplicate_ids(ids: &[u16]) -> bool {
+    let mut i = 0;
+    while i < ids.len() {
+        let mut j = i + 1;
+        while j < ids.len() {
+            if ids[i] == ids[j] {
+                return true;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    false
+}
 
-        buf.append(&mut u16::try_from(id)?.to_be_bytes().to_vec());
-        buf.append(&mut bincode::serialize(&msg)?);
-        Ok(buf)
+/// Returns true if a CommandID is reserved for error replies or built-in commands
+///
+/// Used by `command_id!` to reject reserved IDs at compile time.
+pub const fn has_reserved_ids(ids: &[u16]) -> bool {
+    let mut i = 0;
+    while i < ids.len() {
+        if ids[i] < crate::builtin::FIRST_USER_ID || ids[i] == crate::frame::FATAL_ID {
+            return true;
+        }
+        i += 1;
     }
+    false
 }
 
+// Commands may be given an explicit ID (`Name = 0x0120`),
+// otherwise their ID is FIRST_USER_ID + their index in the declaration.
+// Explicit IDs keep the numbering stable if commands are added or reordered.
 #[macro_export]
 macro_rules! command_id{
     (
-        $($type: ident,)+
+        $($type: ident $(= $id: literal)?,)+
     ) => {
         use std::convert::{TryFrom,TryInto};
         use cubeos_service::variant_count::VariantCount;
         use cubeos_service::{Error as CubeOSError, Result as CubeOSResult};
-        use std::ops::AddAssign;
         use std::fmt;
         use serde::{Serialize,Deserialize};
 
-        // helper functions to implement the TryFrom<u16> for udp and ground macros
-        // increments a usize and outputs the value
-        // needed to increment a counter inside the macro expression $()+ 
-        pub fn increment(i: &mut usize) -> usize {
-            i.add_assign(1);
-            *i-1
-        }
+        // IDs of the user commands, checked at compile time
+        const USER_IDS: &[u16] = &[$(cubeos_service::command_id!(@id $type $($id)?),)+];
+        const _: () = assert!(!cubeos_service::has_duplicate_ids(USER_IDS), "duplicate CommandID");
+        const _: () = assert!(!cubeos_service::has_reserved_ids(USER_IDS), "reserved CommandID");
 
         // Construct CommandID Enum
+        // user commands first, so their discriminant is their index
         #[derive(Clone,Copy,Debug,PartialEq,VariantCount,Serialize,Deserialize)]
         pub enum CommandID {
             $(
                 $type,
             )+
+            Ping,
+            LastCmd,
+            LastErr,
+            LastHistory,
+            Describe,
+            Interface,
+        }
+
+        impl CommandID {
+            // all CommandIDs in declaration order
+            pub const ALL: &'static [CommandID] = &[
+                $(CommandID::$type,)+
+                CommandID::Ping,
+                CommandID::LastCmd,
+                CommandID::LastErr,
+                CommandID::LastHistory,
+                CommandID::Describe,
+                CommandID::Interface,
+            ];
+
+            // numeric ID of the command
+            pub const fn id(self) -> u16 {
+                match self {
+                    $(CommandID::$type => cubeos_service::command_id!(@id $type $($id)?),)+
+                    CommandID::Ping => cubeos_service::PING_ID,
+                    CommandID::LastCmd => cubeos_service::LAST_CMD_ID,
+                    CommandID::LastErr => cubeos_service::LAST_ERR_ID,
+                    CommandID::LastHistory => cubeos_service::LAST_HISTORY_ID,
+                    CommandID::Describe => cubeos_service::DESCRIBE_ID,
+                    CommandID::Interface => cubeos_service::INTERFACE_ID,
+                }
+            }
         }
 
         impl FromStr for CommandID {
@@ -115,6 +187,12 @@ macro_rules! command_id{
                     $(
                         stringify!($type) => Ok(CommandID::$type),
                     )+
+                    "Ping" => Ok(CommandID::Ping),
+                    "LastCmd" => Ok(CommandID::LastCmd),
+                    "LastErr" => Ok(CommandID::LastErr),
+                    "LastHistory" => Ok(CommandID::LastHistory),
+                    "Describe" => Ok(CommandID::Describe),
+                    "Interface" => Ok(CommandID::Interface),
                     _ => Err(CubeOSError::NoCmd)
                 }
             }
@@ -124,12 +202,10 @@ macro_rules! command_id{
             type Error = CubeOSError;
 
             fn try_from(cmd: u16) -> CubeOSResult<Self> {
-                let mut i: usize = 0;
-                let h_field: Vec<u16> = (1..1+CommandID::VARIANT_COUNT as u16).collect();
-                match cmd {
-                    $(x if x == h_field[increment(&mut i)] => Ok(CommandID::$type),)+
-                    _ => Err(CubeOSError::NoCmd),
-                }
+                CommandID::ALL.iter()
+                    .find(|c| c.id() == cmd)
+                    .copied()
+                    .ok_or(CubeOSError::NoCmd)
             }
         }  
         
@@ -138,21 +214,47 @@ macro_rules! command_id{
             type Error = CubeOSError;
 
             fn try_from(c: CommandID) -> CubeOSResult<u16> {
-                let mut i: usize = 0;
-                let h_field: Vec<u16> = (1..1+CommandID::VARIANT_COUNT as u16).collect();
-                match c {
-                    $(CommandID::$type => Ok(h_field[CommandID::$type as usize]),)*
-                    _ => Err(CubeOSError::NoCmd),
-                }
+                Ok(c.id())
             }
         }
 
+        // built-in commands have no arguments
+        #[cfg(feature = "terminal")]
+        #[derive(Debug,Clone,Default,Serialize,Deserialize)]
+        pub struct Ping {}
+        #[cfg(feature = "terminal")]
+        #[derive(Debug,Clone,Default,Serialize,Deserialize)]
+        pub struct LastCmd {}
+        #[cfg(feature = "terminal")]
+        #[derive(Debug,Clone,Default,Serialize,Deserialize)]
+        pub struct LastErr {}
+        #[cfg(feature = "terminal")]
+        #[derive(Debug,Clone,Default,Serialize,Deserialize)]
+        pub struct LastHistory {}
+        #[cfg(feature = "terminal")]
+        #[derive(Debug,Clone,Default,Serialize,Deserialize)]
+        pub struct Describe {}
+        #[cfg(feature = "terminal")]
+        #[derive(Debug,Clone,Default,Serialize,Deserialize)]
+        pub struct Interface {}
+
+        // same order as CommandID
         #[cfg(feature = "terminal")]
         #[derive(Debug,Clone,Serialize,Deserialize)]
         pub enum Command{
             $(
                 $type($type),
             )+
+            Ping(Ping),
+            LastCmd(LastCmd),
+            LastErr(LastErr),
+            LastHistory(LastHistory),
+            Describe(Describe),
+            Interface(Interface),
         }
-    }
+    };
+    // explicit ID
+    (@id $type: ident $id: literal) => { $id };
+    // ID by position in the declaration
+    (@id $type: ident) => { cubeos_service::FIRST_USER_ID + CommandID::$type as u16 };
 }
\ No newline at end of file
@@ -0,0 +1,219 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Contributed by: Patrick Oppel (patrick.oppel94@gmail.com)
+//
+
+// Cubesat Space Protocol (CSP) packets as sent by libcsp 1.x
+//
+// | header u32 BE | payload | CRC-32C u32 BE (optional) |
+//
+// The header holds, from the most significant bit:
+// priority (2), source (5), destination (5), destination port (6),
+// source port (6), reserved (4), HMAC, XTEA, RDP and CRC flags (1 each).
+// The CRC covers the payload. Like libcsp, a CRC that also covers the
+// header is accepted as well.
+
+use crate::codec::crc32c;
+use crate::error::*;
+
+/// Length of the CSP header in bytes
+pub const CSP_HEADER_LEN: usize = 4;
+/// Largest CSP node address
+pub const CSP_MAX_ADDRESS: u8 = 31;
+/// Largest CSP port
+pub const CSP_MAX_PORT: u8 = 63;
+
+const CRC_LEN: usize = 4;
+const FLAG_CRC32: u8 = 0x01;
+const FLAG_RDP: u8 = 0x02;
+const FLAG_XTEA: u8 = 0x04;
+const FLAG_HMAC: u8 = 0x08;
+
+/// Priority field of the CSP header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CspPriority {
+    Critical = 0,
+    High = 1,
+    Normal = 2,
+    Low = 3,
+}
+impl CspPriority {
+    /// Priority named `critical`, `high`, `normal` or `low` in a config file
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "critical" => Some(CspPriority::Critical),
+            "high" => Some(CspPriority::High),
+            "normal" => Some(CspPriority::Normal),
+            "low" => Some(CspPriority::Low),
+            _ => None,
+        }
+    }
+}
+impl From<u8> for CspPriority {
+    fn from(p: u8) -> Self {
+        match p & 0b11 {
+            0 => CspPriority::Critical,
+            1 => CspPriority::High,
+            2 => CspPriority::Normal,
+            _ => CspPriority::Low,
+        }
+    }
+}
+
+/// CSP header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CspHeader {
+    pub priority: CspPriority,
+    pub source: u8,
+    pub destination: u8,
+    pub destination_port: u8,
+    pub source_port: u8,
+    /// Whether the packet ends with a CRC-32C
+    pub crc: bool,
+}
+impl CspHeader {
+    pub fn to_bytes(&self) -> [u8; CSP_HEADER_LEN] {
+        let header = ((self.priority as u32) << 30)
+            | (((self.source & CSP_MAX_ADDRESS) as u32) << 25)
+            | (((self.destination & CSP_MAX_ADDRESS) as u32) << 20)
+            | (((self.destination_port & CSP_MAX_PORT) as u32) << 14)
+            | (((self.source_port & CSP_MAX_PORT) as u32) << 8)
+            | if self.crc { FLAG_CRC32 as u32 } else { 0 };
+        header.to_be_bytes()
+    }
+
+    /// Parses the header of a packet
+    ///
+    /// Packets using RDP, XTEA or HMAC are rejected with `Error::InvalidPacket`.
+    pub fn parse(msg: &[u8]) -> Result<CspHeader> {
+        if msg.len() < CSP_HEADER_LEN {
+            return Err(Error::Truncated);
+        }
+        let header = u32::from_be_bytes([msg[0], msg[1], msg[2], msg[3]]);
+        let flags = msg[3];
+        if flags & (FLAG_RDP | FLAG_XTEA | FLAG_HMAC) != 0 {
+            return Err(Error::InvalidPacket);
+        }
+        Ok(CspHeader {
+            priority: CspPriority::from((header >> 30) as u8),
+            source: ((header >> 25) as u8) & CSP_MAX_ADDRESS,
+            destination: ((header >> 20) as u8) & CSP_MAX_ADDRESS,
+            destination_port: ((header >> 14) as u8) & CSP_MAX_PORT,
+            source_port: ((header >> 8) as u8) & CSP_MAX_PORT,
+            crc: flags & FLAG_CRC32 != 0,
+        })
+    }
+
+    /// Encodes a packet with this header
+    pub fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(CSP_HEADER_LEN + payload.len() + CRC_LEN);
+        packet.extend_from_slice(&self.to_bytes());
+        packet.extend_from_slice(payload);
+        if self.crc {
+            packet.extend_from_slice(&crc32c(payload).to_be_bytes());
+        }
+        packet
+    }
+}
+
+/// Decodes a CSP packet into its header and payload
+///
+/// Returns `Error::Checksum` if the packet carries a CRC that does not match.
+pub fn decode_csp(msg: &[u8]) -> Result<(CspHeader, &[u8])> {
+    let header = CspHeader::parse(msg)?;
+    if !header.crc {
+        return Ok((header, &msg[CSP_HEADER_LEN..]));
+    }
+    if msg.len() < CSP_HEADER_LEN + CRC_LEN {
+        return Err(Error::Truncated);
+    }
+    let (data, trailer) = msg.split_at(msg.len() - CRC_LEN);
+    let crc = u32::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let payload = &data[CSP_HEADER_LEN..];
+    match crc == crc32c(payload) || crc == crc32c(data) {
+        true => Ok((header, payload)),
+        false => Err(Error::Checksum),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // priority normal, node 1 to node 2, port 20 to port 10, CRC flag,
+    // as packed into the csp_id_t of libcsp 1.x
+    const LIBCSP_HEADER: [u8; 4] = [0x82, 0x22, 0x94, 0x01];
+
+    fn header() -> CspHeader {
+        CspHeader {
+            priority: CspPriority::Normal,
+            source: 1,
+            destination: 2,
+            destination_port: 10,
+            source_port: 20,
+            crc: true,
+        }
+    }
+
+    #[test]
+    fn header_layout() {
+        assert_eq!(header().to_bytes(), LIBCSP_HEADER);
+        assert_eq!(CspHeader::parse(&LIBCSP_HEADER).unwrap(), header());
+        let fields = CspHeader {
+            priority: CspPriority::Low,
+            source: CSP_MAX_ADDRESS,
+            destination: 0,
+            destination_port: CSP_MAX_PORT,
+            source_port: 0,
+            crc: false,
+        };
+        assert_eq!(fields.to_bytes(), [0xFE, 0x0F, 0xC0, 0x00]);
+        assert_eq!(CspHeader::parse(&fields.to_bytes()).unwrap(), fields);
+    }
+
+    #[test]
+    fn crc_variants() {
+        let payload = b"123456789";
+        // CRC over the payload, as sent by libcsp
+        let packet = header().encode(payload);
+        assert_eq!(packet[packet.len() - 4..], 0xE306_9283u32.to_be_bytes());
+        assert_eq!(decode_csp(&packet).unwrap(), (header(), &payload[..]));
+        // CRC over header and payload
+        let mut packet = LIBCSP_HEADER.to_vec();
+        packet.extend_from_slice(payload);
+        let crc = crc32c(&packet);
+        packet.extend_from_slice(&crc.to_be_bytes());
+        assert_eq!(decode_csp(&packet).unwrap(), (header(), &payload[..]));
+        // any other CRC
+        let last = packet.len() - 1;
+        packet[last] ^= 0x01;
+        assert_eq!(decode_csp(&packet), Err(Error::Checksum));
+        // without the CRC flag the packet ends with the payload
+        let plain = CspHeader { crc: false, ..header() };
+        assert_eq!(decode_csp(&plain.encode(payload)).unwrap(), (plain, &payload[..]));
+    }
+
+    #[test]
+    fn rejected() {
+        assert_eq!(CspHeader::parse(&LIBCSP_HEADER[..3]), Err(Error::Truncated));
+        assert_eq!(decode_csp(&LIBCSP_HEADER), Err(Error::Truncated));
+        for flag in [FLAG_RDP, FLAG_XTEA, FLAG_HMAC] {
+            let mut packet = LIBCSP_HEADER;
+            packet[3] |= flag;
+            assert_eq!(CspHeader::parse(&packet), Err(Error::InvalidPacket));
+        }
+    }
+}
@@ -77,6 +77,42 @@ pub enum Error {
     /// Diesel
     #[fail(display = "Diesel Error")]
     Diesel(u8),
+    /// Message shorter than frame header or declared payload
+    #[fail(display = "Truncated Frame")]
+    Truncated,
+    /// Declared payload exceeds maximum frame size
+    #[fail(display = "Oversized Frame")]
+    Oversized,
+    /// Message longer than declared payload
+    #[fail(display = "Trailing Bytes in Frame")]
+    TrailingBytes,
+    /// Checksum trailer does not match the frame
+    #[fail(display = "Checksum Mismatch")]
+    Checksum,
+    /// Invalid value in the service config
+    #[fail(display = "Invalid Config {}", _0)]
+    InvalidConfig(String),
+    /// Malformed Space Packet or wrong APID
+    #[fail(display = "Invalid Space Packet")]
+    InvalidPacket,
+    /// Missing or invalid authentication of a frame
+    #[fail(display = "Unauthenticated Command")]
+    Unauthenticated,
+    /// Counter of an authenticated command outside the replay window
+    #[fail(display = "Replayed Command")]
+    Replay,
+    /// Request queue of the service is full
+    #[fail(display = "Service busy")]
+    Busy,
+    /// Command exceeded its timeout
+    #[fail(display = "Command timed out")]
+    TimedOut,
+    /// Command table of the client differs from the service
+    #[fail(display = "Interface mismatch")]
+    InterfaceMismatch,
+    /// No reply from the service within the timeout of the call
+    #[fail(display = "No reply")]
+    NoReply,
 }
 impl From<failure::Error> for Error {
     fn from(e: failure::Error) -> Error {
@@ -250,6 +286,11 @@ impl From<uart_rs::UartError> for Error {
         }
     }
 }
+impl From<serial::Error> for Error {
+    fn from(e: serial::Error) -> Error {
+        Error::from(uart_rs::UartError::SerialError(e.kind()))
+    }
+}
 // impl From<Error> for rust_uart::UartError {
 //     fn from(e: Error) -> rust_uart::UartError {
 //         match e {
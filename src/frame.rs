@@ -0,0 +1,177 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Contributed by: Patrick Oppel (patrick.oppel94@gmail.com)
+//
+
+// Framing of Command messages
+//
+// Every message exchanged between a service and its clients is framed as
+//
+// | CommandID (u16 BE) | sequence number (u16 BE) | payload length (u32 BE) | payload |
+//
+// The sequence number is chosen by the client and echoed in the reply,
+// so late replies to an earlier request can be told apart.
+// The payload is the bincode serialized argument tuple (requests),
+// the reply value (replies) or an Error (CommandID 0 and 65535).
+// The frame is validated before any byte of the payload is touched,
+// so a malformed datagram results in an Error instead of a panic.
+
+use crate::error::*;
+use serde::{Serialize,Deserialize};
+use std::sync::atomic::{AtomicU16, Ordering};
+
+/// Length of the frame header in bytes
+pub const HEADER_LEN: usize = 8;
+/// Maximum payload length accepted in a single frame
+pub const MAX_PAYLOAD: usize = 0x0010_0000;
+/// CommandID used for error replies
+pub const ERROR_ID: u16 = 0;
+/// CommandID used if the error itself could not be serialized
+pub const FATAL_ID: u16 = 0xFFFF;
+
+// Sequence number of the next request sent by this process
+static NEXT_SEQ: AtomicU16 = AtomicU16::new(1);
+
+/// Returns a fresh sequence number for a request
+pub fn next_seq() -> u16 {
+    NEXT_SEQ.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Validated view into a received message
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame<'a> {
+    /// Raw CommandID
+    pub id: u16,
+    /// Sequence number of the request
+    pub seq: u16,
+    /// Payload, excluding header
+    pub payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    /// Validates the header of `msg` and returns the contained frame
+    ///
+    /// # Errors
+    ///
+    /// `Error::Truncated` if `msg` is shorter than the header or the declared payload,
+    /// `Error::Oversized` if the declared payload exceeds `MAX_PAYLOAD`,
+    /// `Error::TrailingBytes` if `msg` is longer than header and declared payload.
+    pub fn parse(msg: &'a [u8]) -> Result<Frame<'a>> {
+        if msg.len() < HEADER_LEN {
+            return Err(Error::Truncated);
+        }
+        let id = u16::from_be_bytes([msg[0], msg[1]]);
+        let seq = u16::from_be_bytes([msg[2], msg[3]]);
+        let len = u32::from_be_bytes([msg[4], msg[5], msg[6], msg[7]]) as usize;
+        if len > MAX_PAYLOAD {
+            return Err(Error::Oversized);
+        }
+        match (msg.len() - HEADER_LEN).cmp(&len) {
+            std::cmp::Ordering::Less => Err(Error::Truncated),
+            std::cmp::Ordering::Greater => Err(Error::TrailingBytes),
+            std::cmp::Ordering::Equal => Ok(Frame {
+                id,
+                seq,
+                payload: &msg[HEADER_LEN..],
+            }),
+        }
+    }
+
+    /// Returns the sequence number of a message without validating it,
+    /// used to address error replies to malformed requests
+    pub fn peek_seq(msg: &[u8]) -> Option<u16> {
+        match msg.len() >= 4 {
+            true => Some(u16::from_be_bytes([msg[2], msg[3]])),
+            false => None,
+        }
+    }
+
+    /// Builds a frame from a raw CommandID, sequence number and an already serialized payload
+    pub fn encode(id: u16, seq: u16, payload: &[u8]) -> Result<Vec<u8>> {
+        if payload.len() > MAX_PAYLOAD {
+            return Err(Error::Oversized);
+        }
+        let mut buf: Vec<u8> = Vec::with_capacity(HEADER_LEN + payload.len());
+        buf.extend_from_slice(&id.to_be_bytes());
+        buf.extend_from_slice(&seq.to_be_bytes());
+        buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(payload);
+        Ok(buf)
+    }
+
+    /// Returns true if the frame carries an error reply
+    pub fn is_error(&self) -> bool {
+        self.id == ERROR_ID || self.id == FATAL_ID
+    }
+
+    /// Decodes the error carried in an error reply
+    pub fn error(&self) -> Error {
+        match self.id {
+            ERROR_ID => match decode_payload::<Error>(self.payload) {
+                Ok(e) => e,
+                Err(e) => e,
+            },
+            _ => Error::Bincode(self.payload.first().copied().unwrap_or(8)),
+        }
+    }
+
+    /// Decodes the payload of the frame
+    pub fn data<T: Serialize + Deserialize<'a>>(&self) -> Result<T> {
+        decode_payload(self.payload)
+    }
+
+    /// Returns a decoder for the arguments of a command carried in the payload
+    pub fn args(&self) -> ArgDecoder<'a> {
+        ArgDecoder { payload: self.payload }
+    }
+}
+
+/// Decodes the arguments of a command one after another
+///
+/// The arguments are encoded the same as a tuple or struct of all arguments,
+/// so a command taking an argument struct and a command taking its fields as
+/// separate arguments accept the same payload. Unlike tuples, the number of
+/// arguments is not limited.
+pub struct ArgDecoder<'a> {
+    payload: &'a [u8],
+}
+
+impl<'a> ArgDecoder<'a> {
+    /// Decodes the next argument
+    pub fn next<T: Serialize + Deserialize<'a>>(&mut self) -> Result<T> {
+        let data = bincode::deserialize::<T>(self.payload)?;
+        let len = bincode::serialized_size(&data)? as usize;
+        self.payload = self.payload.get(len..).ok_or(Error::Truncated)?;
+        Ok(data)
+    }
+
+    /// Makes sure all of the payload was consumed
+    pub fn finish(self) -> Result<()> {
+        match self.payload.is_empty() {
+            true => Ok(()),
+            false => Err(Error::TrailingBytes),
+        }
+    }
+}
+
+/// Deserializes `payload` and makes sure all of it was consumed
+pub fn decode_payload<'a, T: Serialize + Deserialize<'a>>(payload: &'a [u8]) -> Result<T> {
+    let data = bincode::deserialize::<T>(payload)?;
+    if bincode::serialized_size(&data)? as usize != payload.len() {
+        return Err(Error::TrailingBytes);
+    }
+    Ok(data)
+}
@@ -0,0 +1,282 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Contributed by: Patrick Oppel (patrick.oppel94@gmail.com)
+//
+
+// Framing of messages on a byte stream like a serial link
+//
+// SLIP (RFC 1055) delimits a message with END bytes and escapes END and ESC
+// within it. KISS uses the same delimiter and escapes, followed by a command
+// byte of which only data frames on port 0 are used.
+//
+// | END | (KISS: 0x00) | escaped message | END |
+//
+// On reliable streams like TCP each message is preceded by its length instead:
+//
+// | len u32 BE | message |
+
+use crate::frame::{HEADER_LEN, MAX_PAYLOAD};
+use std::collections::VecDeque;
+use std::io::{self, Read};
+
+const END: u8 = 0xC0;
+const ESC: u8 = 0xDB;
+const ESC_END: u8 = 0xDC;
+const ESC_ESC: u8 = 0xDD;
+// KISS command byte of a data frame on port 0
+const KISS_DATA: u8 = 0x00;
+
+/// Longest message accepted by a `Deframer`, the size of the largest UDP datagram
+pub const MAX_MESSAGE: usize = u16::MAX as usize;
+
+/// Longest length-prefixed message, a frame of `MAX_PAYLOAD` with room for
+/// the headers, authentication and checksum added by the `Codec`
+pub const MAX_STREAM_MESSAGE: usize = HEADER_LEN + MAX_PAYLOAD + 256;
+
+/// Framing of messages on a byte stream
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Framing {
+    Slip,
+    Kiss,
+}
+
+impl Framing {
+    /// Framing named `slip` or `kiss` in a config file
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "slip" => Some(Framing::Slip),
+            "kiss" => Some(Framing::Kiss),
+            _ => None,
+        }
+    }
+
+    /// Frames a message for transmission
+    pub fn encode(&self, msg: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(msg.len() + 4);
+        // a leading END flushes noise received before the message
+        frame.push(END);
+        if *self == Framing::Kiss {
+            frame.push(KISS_DATA);
+        }
+        for b in msg {
+            match *b {
+                END => frame.extend_from_slice(&[ESC, ESC_END]),
+                ESC => frame.extend_from_slice(&[ESC, ESC_ESC]),
+                b => frame.push(b),
+            }
+        }
+        frame.push(END);
+        frame
+    }
+}
+
+/// Reassembles messages from the bytes received on a stream
+///
+/// Messages with an invalid escape sequence, longer than `MAX_MESSAGE`,
+/// or KISS frames other than data frames on port 0 are dropped.
+#[derive(Debug)]
+pub struct Deframer {
+    framing: Framing,
+    buf: Vec<u8>,
+    escaped: bool,
+    // the current message is dropped at the next END
+    discard: bool,
+    messages: VecDeque<Vec<u8>>,
+}
+
+impl Deframer {
+    pub fn new(framing: Framing) -> Self {
+        Deframer {
+            framing,
+            buf: Vec::new(),
+            escaped: false,
+            discard: false,
+            messages: VecDeque::new(),
+        }
+    }
+
+    /// Adds received bytes
+    pub fn push(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            match (*b, self.escaped) {
+                (END, _) => self.finish(),
+                (ESC, false) => self.escaped = true,
+                (ESC_END, true) => self.append(END),
+                (ESC_ESC, true) => self.append(ESC),
+                (_, true) => {
+                    log::debug!("Drop message with invalid escape sequence");
+                    self.discard = true;
+                    self.escaped = false;
+                }
+                (b, false) => self.append(b),
+            }
+        }
+    }
+
+    /// Takes the next complete message
+    pub fn next_message(&mut self) -> Option<Vec<u8>> {
+        self.messages.pop_front()
+    }
+
+    fn append(&mut self, b: u8) {
+        self.escaped = false;
+        // a KISS frame holds the command byte in front of the message
+        let limit = match self.framing {
+            Framing::Slip => MAX_MESSAGE,
+            Framing::Kiss => MAX_MESSAGE + 1,
+        };
+        if self.buf.len() >= limit {
+            self.discard = true;
+        }
+        if !self.discard {
+            self.buf.push(b);
+        }
+    }
+
+    fn finish(&mut self) {
+        let msg = std::mem::take(&mut self.buf);
+        let discard = std::mem::replace(&mut self.discard, false);
+        self.escaped = false;
+        // empty messages are the delimiters of consecutive frames
+        if discard || msg.is_empty() {
+            return;
+        }
+        match self.framing {
+            Framing::Slip => self.messages.push_back(msg),
+            Framing::Kiss if msg[0] == KISS_DATA => self.messages.push_back(msg[1..].to_vec()),
+            Framing::Kiss => log::debug!("Drop KISS frame with command {:#04x}", msg[0]),
+        }
+    }
+}
+
+/// Prefixes a message with its length
+pub fn encode_prefixed(msg: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(4 + msg.len());
+    frame.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+    frame.extend_from_slice(msg);
+    frame
+}
+
+/// Takes the first complete length-prefixed message from the bytes received so far
+///
+/// Fails with `InvalidData` if the message is longer than `MAX_STREAM_MESSAGE`,
+/// the stream cannot be resynchronized after that.
+pub fn take_prefixed(buf: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+    let len = prefix_len([buf[0], buf[1], buf[2], buf[3]])?;
+    if buf.len() < 4 + len {
+        return Ok(None);
+    }
+    let msg = buf[4..4 + len].to_vec();
+    buf.drain(..4 + len);
+    Ok(Some(msg))
+}
+
+/// Reads the next length-prefixed message from a blocking stream
+pub fn read_prefixed<R: Read>(stream: &mut R) -> io::Result<Vec<u8>> {
+    let mut prefix = [0u8; 4];
+    stream.read_exact(&mut prefix)?;
+    let mut msg = vec![0u8; prefix_len(prefix)?];
+    stream.read_exact(&mut msg)?;
+    Ok(msg)
+}
+
+fn prefix_len(prefix: [u8; 4]) -> io::Result<usize> {
+    match u32::from_be_bytes(prefix) as usize {
+        len if len > MAX_STREAM_MESSAGE => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message of {} bytes exceeds {}", len, MAX_STREAM_MESSAGE),
+        )),
+        len => Ok(len),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes() {
+        let msg = [0x01, END, 0x02, ESC, 0x03];
+        assert_eq!(
+            Framing::Slip.encode(&msg),
+            vec![END, 0x01, ESC, ESC_END, 0x02, ESC, ESC_ESC, 0x03, END]
+        );
+        assert_eq!(
+            Framing::Kiss.encode(&msg),
+            vec![END, KISS_DATA, 0x01, ESC, ESC_END, 0x02, ESC, ESC_ESC, 0x03, END]
+        );
+        for framing in [Framing::Slip, Framing::Kiss] {
+            let mut deframer = Deframer::new(framing);
+            deframer.push(&framing.encode(&msg));
+            assert_eq!(deframer.next_message(), Some(msg.to_vec()));
+            assert_eq!(deframer.next_message(), None);
+        }
+    }
+
+    #[test]
+    fn split_reads() {
+        let mut stream = Framing::Kiss.encode(&[END, ESC, 0x42]);
+        stream.extend(Framing::Kiss.encode(&[0x07]));
+        let mut deframer = Deframer::new(Framing::Kiss);
+        // one byte at a time, splitting every escape sequence
+        for b in &stream {
+            deframer.push(&[*b]);
+        }
+        assert_eq!(deframer.next_message(), Some(vec![END, ESC, 0x42]));
+        assert_eq!(deframer.next_message(), Some(vec![0x07]));
+        assert_eq!(deframer.next_message(), None);
+    }
+
+    #[test]
+    fn invalid_frames() {
+        let mut deframer = Deframer::new(Framing::Kiss);
+        // invalid escape sequence, then a KISS command other than data
+        deframer.push(&[END, KISS_DATA, ESC, 0x01, END]);
+        deframer.push(&[END, 0x01, 0x02, END]);
+        deframer.push(&Framing::Kiss.encode(&[0x03]));
+        assert_eq!(deframer.next_message(), Some(vec![0x03]));
+        assert_eq!(deframer.next_message(), None);
+    }
+
+    #[test]
+    fn oversize() {
+        for framing in [Framing::Slip, Framing::Kiss] {
+            let mut deframer = Deframer::new(framing);
+            deframer.push(&framing.encode(&vec![0x11; MAX_MESSAGE]));
+            assert_eq!(deframer.next_message().map(|m| m.len()), Some(MAX_MESSAGE));
+            deframer.push(&framing.encode(&vec![0x11; MAX_MESSAGE + 1]));
+            assert_eq!(deframer.next_message(), None);
+            // the stream recovers at the next END
+            deframer.push(&framing.encode(&[0x22]));
+            assert_eq!(deframer.next_message(), Some(vec![0x22]));
+        }
+    }
+
+    #[test]
+    fn prefixed() {
+        let mut buf = encode_prefixed(&[1, 2, 3]);
+        buf.extend(encode_prefixed(&[4]));
+        let mut partial = buf[..5].to_vec();
+        assert_eq!(take_prefixed(&mut partial).unwrap(), None);
+        assert_eq!(take_prefixed(&mut buf).unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(read_prefixed(&mut &buf[..]).unwrap(), vec![4]);
+        let mut long = ((MAX_STREAM_MESSAGE + 1) as u32).to_be_bytes().to_vec();
+        assert!(take_prefixed(&mut long).is_err());
+    }
+}
@@ -0,0 +1,201 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Contributed by: Patrick Oppel (patrick.oppel94@gmail.com)
+//
+
+// Description of the commands of a service
+//
+// The generated `describe()` lists the built-in commands followed by the commands
+// declared in `service_macro!`. The built-in Describe command replies this list,
+// so ground tools can discover the interface of a service at runtime.
+// Types are given as written in the `service_macro!`.
+//
+// The interface hash covers the name, CommandID, argument types and reply type
+// of every declared command. `service_macro!`, the terminal `service_macro!` and
+// `app_macro!` compute it at compile time as `INTERFACE_HASH`, the service replies
+// it to the built-in Interface command. A client built from a different revision
+// of the command table can detect the mismatch before it sends commands that would
+// decode into garbage.
+// Types are hashed with their full path as written, only whitespace is ignored, so
+// `subsystem::Cfg` and `other::Cfg` differ. The hash does not depend on the order
+// of the commands.
+
+use crate::builtin::*;
+use crate::client::transfer;
+use crate::codec::Codec;
+use crate::error::{Error, Result};
+use crate::frame::{next_seq, Frame};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+// FNV-1a
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Query or mutation
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum CommandKind {
+    Query,
+    Mutation,
+}
+
+// CommandKind of a query or mutation
+#[macro_export]
+macro_rules! command_kind {
+    (query) => { $crate::CommandKind::Query };
+    (mutation) => { $crate::CommandKind::Mutation };
+}
+
+/// Name and type of a command argument
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ArgInfo {
+    pub name: String,
+    pub ty: String,
+}
+
+/// Description of a command
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CommandInfo {
+    pub name: String,
+    pub id: u16,
+    pub kind: CommandKind,
+    pub args: Vec<ArgInfo>,
+    pub reply: String,
+}
+
+impl CommandInfo {
+    pub fn new(name: &str, id: u16, kind: CommandKind, args: &[(&str, &str)], reply: &str) -> Self {
+        CommandInfo {
+            name: name.to_string(),
+            id,
+            kind,
+            args: args
+                .iter()
+                .map(|(name, ty)| ArgInfo {
+                    name: name.to_string(),
+                    ty: ty.to_string(),
+                })
+                .collect(),
+            reply: reply.to_string(),
+        }
+    }
+}
+
+/// Descriptions of the built-in commands
+pub fn builtin_commands() -> Vec<CommandInfo> {
+    vec![
+        CommandInfo::new("Ping", PING_ID, CommandKind::Query, &[], "()"),
+        CommandInfo::new("LastCmd", LAST_CMD_ID, CommandKind::Query, &[], "Vec<u8>"),
+        CommandInfo::new("LastErr", LAST_ERR_ID, CommandKind::Query, &[], "Error"),
+        CommandInfo::new("LastHistory", LAST_HISTORY_ID, CommandKind::Query, &[], "History"),
+        CommandInfo::new("Describe", DESCRIBE_ID, CommandKind::Query, &[], "Vec<CommandInfo>"),
+        CommandInfo::new("Interface", INTERFACE_ID, CommandKind::Query, &[], "u64"),
+    ]
+}
+
+const fn hash_byte(hash: u64, byte: u8) -> u64 {
+    (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+}
+
+// Hashes a type name with its full path, without whitespace
+const fn hash_type(mut hash: u64, ty: &str) -> u64 {
+    let b = ty.as_bytes();
+    let mut i = 0;
+    while i < b.len() {
+        if !b[i].is_ascii_whitespace() {
+            hash = hash_byte(hash, b[i]);
+        }
+        i += 1;
+    }
+    hash
+}
+
+/// Hash of a command with its CommandID, argument types and reply type
+pub const fn command_hash(name: &str, id: u16, args: &[&str], reply: &str) -> u64 {
+    let mut hash = FNV_OFFSET;
+    let n = name.as_bytes();
+    let mut i = 0;
+    while i < n.len() {
+        hash = hash_byte(hash, n[i]);
+        i += 1;
+    }
+    let id = id.to_be_bytes();
+    hash = hash_byte(hash_byte(hash, id[0]), id[1]);
+    let mut i = 0;
+    while i < args.len() {
+        hash = hash_type(hash_byte(hash, b';'), args[i]);
+        i += 1;
+    }
+    hash_type(hash_byte(hash, b'>'), reply)
+}
+
+/// Hash of the command table, independent of the order of the commands
+pub const fn interface_hash(commands: &[u64]) -> u64 {
+    let mut hash = 0u64;
+    let mut i = 0;
+    while i < commands.len() {
+        hash = hash.wrapping_add(commands[i]);
+        i += 1;
+    }
+    hash
+}
+
+/// Requests the interface hash of the service at `to`
+pub fn remote_interface(
+    socket: &UdpSocket,
+    to: &SocketAddr,
+    codec: &Codec,
+    timeout: Option<Duration>,
+) -> Result<u64> {
+    let reply = transfer(socket, to, codec, Frame::encode(INTERFACE_ID, next_seq(), &[])?, timeout)?;
+    let frame = Frame::parse(&reply)?;
+    if frame.is_error() {
+        return Err(frame.error());
+    }
+    frame.data::<u64>()
+}
+
+/// Compares the interface hash of the service at `to` with `local`
+///
+/// Returns `Error::InterfaceMismatch` if the hashes differ.
+/// Services that predate the Interface command are accepted with a warning.
+pub fn verify_interface(
+    socket: &UdpSocket,
+    to: &SocketAddr,
+    codec: &Codec,
+    local: u64,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    check_interface(to, remote_interface(socket, to, codec, timeout), local)
+}
+
+// Compares the reply to the Interface command with the local interface hash
+pub(crate) fn check_interface(to: &impl std::fmt::Display, remote: Result<u64>, local: u64) -> Result<()> {
+    match remote {
+        Ok(remote) if remote == local => Ok(()),
+        Ok(remote) => {
+            warn!("Interface of service at {} is {:#018x}, expected {:#018x}", to, remote, local);
+            Err(Error::InterfaceMismatch)
+        }
+        Err(Error::NoCmd) => {
+            warn!("Service at {} does not report its interface", to);
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
@@ -1,8 +1,156 @@
 use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Request executed by a service
+///
+/// Gives the built-in commands the sender of the request and the recent commands
+/// and errors of the service that executes it, so services in one process keep
+/// separate histories.
+#[derive(Clone)]
+pub(crate) struct Scope {
+    pub(crate) sender: Option<SocketAddr>,
+    pub(crate) last: Arc<LastTracker>,
+}
+
+thread_local! {
+    // request executed on this thread
+    static SCOPE: RefCell<Option<Scope>> = const { RefCell::new(None) };
+}
+
+#[cfg(feature = "async")]
+tokio::task_local! {
+    // request executed in this task
+    pub(crate) static TASK_SCOPE: Scope;
+}
+
+/// Sets the request executed on the current thread
+pub(crate) fn set_scope(scope: Option<Scope>) {
+    SCOPE.with(|s| *s.borrow_mut() = scope);
+}
+
+// Request executed in the current task or on the current thread
+pub(crate) fn current_scope() -> Option<Scope> {
+    #[cfg(feature = "async")]
+    if let Ok(scope) = TASK_SCOPE.try_with(|s| s.clone()) {
+        return Some(scope);
+    }
+    SCOPE.with(|s| s.borrow().clone())
+}
 
 pub trait Last {
     fn set_last_cmd(&self, input: Vec<u8>);
     fn get_last_cmd(&self) -> Result<Vec<u8>>;
     fn set_last_err(&self, err: Error);
     fn get_last_err(&self) -> Result<Error>;
-}
\ No newline at end of file
+
+    /// Returns the recent commands and errors, oldest first
+    ///
+    /// Defaults to the last command and error without time and sender.
+    fn get_last_history(&self) -> Result<History> {
+        Ok(History {
+            commands: vec![LastEntry::new(None, None, self.get_last_cmd()?)],
+            errors: vec![LastEntry::new(None, None, self.get_last_err()?)],
+        })
+    }
+}
+
+/// Recorded command or error
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LastEntry<T> {
+    pub time: Option<SystemTime>,
+    pub sender: Option<SocketAddr>,
+    pub value: T,
+}
+impl<T> LastEntry<T> {
+    pub fn new(time: Option<SystemTime>, sender: Option<SocketAddr>, value: T) -> Self {
+        LastEntry { time, sender, value }
+    }
+}
+
+/// Reply of the built-in LastHistory command
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct History {
+    pub commands: Vec<LastEntry<Vec<u8>>>,
+    pub errors: Vec<LastEntry<Error>>,
+}
+
+/// Thread-safe implementation of `Last`
+///
+/// Keeps the last `capacity` commands and errors with the time they were recorded
+/// and the address of the client that sent the request.
+///
+/// ```rust,ignore
+/// struct Subsystem {
+///     last: LastTracker,
+/// }
+/// impl Last for Subsystem {
+///     fn set_last_cmd(&self, input: Vec<u8>) { self.last.set_last_cmd(input) }
+///     fn get_last_cmd(&self) -> Result<Vec<u8>> { self.last.get_last_cmd() }
+///     fn set_last_err(&self, err: Error) { self.last.set_last_err(err) }
+///     fn get_last_err(&self) -> Result<Error> { self.last.get_last_err() }
+///     fn get_last_history(&self) -> Result<History> { self.last.get_last_history() }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct LastTracker {
+    capacity: usize,
+    commands: Mutex<VecDeque<LastEntry<Vec<u8>>>>,
+    errors: Mutex<VecDeque<LastEntry<Error>>>,
+}
+
+impl LastTracker {
+    pub fn new(capacity: usize) -> Self {
+        LastTracker {
+            capacity,
+            commands: Mutex::new(VecDeque::with_capacity(capacity)),
+            errors: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    fn push<T>(&self, buffer: &Mutex<VecDeque<LastEntry<T>>>, value: T) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Ok(mut buffer) = buffer.lock() {
+            if buffer.len() >= self.capacity {
+                buffer.pop_front();
+            }
+            let sender = current_scope().and_then(|s| s.sender);
+            buffer.push_back(LastEntry::new(Some(SystemTime::now()), sender, value));
+        }
+    }
+}
+
+impl Last for LastTracker {
+    fn set_last_cmd(&self, input: Vec<u8>) {
+        self.push(&self.commands, input)
+    }
+
+    fn get_last_cmd(&self) -> Result<Vec<u8>> {
+        let commands = self.commands.lock().map_err(|_| Error::PoisonedMutex)?;
+        Ok(commands.back().map(|e| e.value.clone()).unwrap_or_default())
+    }
+
+    fn set_last_err(&self, err: Error) {
+        self.push(&self.errors, err)
+    }
+
+    fn get_last_err(&self) -> Result<Error> {
+        let errors = self.errors.lock().map_err(|_| Error::PoisonedMutex)?;
+        Ok(errors.back().map(|e| e.value.clone()).unwrap_or(Error::None))
+    }
+
+    fn get_last_history(&self) -> Result<History> {
+        let commands = self.commands.lock().map_err(|_| Error::PoisonedMutex)?;
+        let errors = self.errors.lock().map_err(|_| Error::PoisonedMutex)?;
+        Ok(History {
+            commands: commands.iter().cloned().collect(),
+            errors: errors.iter().cloned().collect(),
+        })
+    }
+}
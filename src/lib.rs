@@ -119,7 +119,10 @@ pub use ::udp_rs;
 pub use ::serde;
 pub use ::std::convert;
 pub use ::variant_count;
+pub use ::cubeos_service_macro::cubeos_service;
 
+#[cfg(feature = "async")]
+pub use ::tokio;
 #[cfg(feature = "terminal")]
 pub use ::dialoguer;
 #[cfg(feature = "terminal")]
@@ -137,19 +140,45 @@ mod app;
 // #[cfg(any(feature = "default", feature = "terminal"))]
 mod service;
 
+mod address;
+mod auth;
+mod builtin;
+mod client;
+#[cfg(feature = "async")]
+mod async_client;
+mod codec;
 mod command;
+mod csp;
+mod frame;
+mod framing;
+mod interface;
 mod last;
 mod ping;
+mod replay;
+mod spacepacket;
 mod error;
 
 pub use crate::error::{Error,Result};
+pub use crate::address::Address;
+pub use crate::frame::{Frame,ArgDecoder,decode_payload,next_seq};
+pub use crate::client::{transfer,CallOptions,Client,ClientBuilder,CONNECT_TIMEOUT,DEFAULT_CALL_TIMEOUT};
+#[cfg(feature = "async")]
+pub use crate::async_client::AsyncClient;
+pub use crate::codec::{Codec,Checksum,Format};
+pub use crate::csp::{CspHeader,CspPriority,decode_csp,CSP_HEADER_LEN,CSP_MAX_ADDRESS,CSP_MAX_PORT};
+pub use crate::framing::{Framing,Deframer,MAX_MESSAGE,MAX_STREAM_MESSAGE,encode_prefixed,take_prefixed,read_prefixed};
+pub use crate::auth::Key;
+pub use crate::builtin::{Builtin,PingBuiltin,PingDefault,LastBuiltin,LastDefault,PING_ID,LAST_CMD_ID,LAST_ERR_ID,LAST_HISTORY_ID,DESCRIBE_ID,INTERFACE_ID,FIRST_USER_ID};
+pub use crate::interface::{CommandInfo,CommandKind,ArgInfo,builtin_commands,command_hash,interface_hash,remote_interface,verify_interface};
+pub use crate::replay::{Counter,ReplayWindow};
+pub use crate::spacepacket::{SpacePacket,PrimaryHeader,PacketType,SequenceFlags,Timestamp};
 pub use crate::ping::*;
 pub use crate::last::*;
 // #[cfg(any(feature = "default", feature = "terminal"))]
 pub use crate::service::*;
 // #[cfg(feature = "app")]
 pub use crate::app::*;
-#[cfg(not(feature = "terminal"))]
 pub use crate::command::Command;
+pub use crate::command::{has_duplicate_ids,has_reserved_ids};
 pub use kubos_system::logger as Logger;
 pub use kubos_system::Config;
@@ -0,0 +1,324 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Contributed by: Patrick Oppel (patrick.oppel94@gmail.com)
+//
+
+// Replay protection of authenticated commands
+//
+// Clients sign every command with a monotonic counter (`Counter`).
+// The service accepts a command only if its counter is above the last accepted
+// counter of the same key and at most `replay_window` ahead of it (`ReplayWindow`).
+// Both sides keep their counters in small state files so they survive a restart,
+// the files are required if an `auth_key` is configured. Clients sharing a counter
+// file, e.g. several apps using the same key, lock it and read the last sent counter
+// before every increment, so they never send the same counter twice.
+//
+// ```toml,ignore
+// [service-name]
+// auth_counter = "/var/lib/cubeos/example-service.counter"  # client: last sent counter
+// replay_state = "/var/lib/cubeos/example-service.replay"   # service: last accepted counters
+// replay_window = 1000                                      # service: optional, unlimited if not set
+// ```
+
+use crate::error::*;
+use kubos_system::Config;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+fn path_from_config(config: &Config, key: &str) -> Result<PathBuf> {
+    config
+        .get(key)
+        .and_then(|v| v.as_str().filter(|p| !p.is_empty()).map(PathBuf::from))
+        .ok_or_else(|| Error::InvalidConfig(key.to_string()))
+}
+
+fn load_counter(path: &Path) -> Result<u32> {
+    match path.exists() {
+        true => fs::read_to_string(path)?
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| Error::InvalidConfig(path.display().to_string())),
+        false => Ok(0),
+    }
+}
+
+// `<path>.<suffix>`, keeping the extension of `path` so the files of the
+// counter and the replay window of one service do not collide
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    PathBuf::from(format!("{}.{}", path.display(), suffix))
+}
+
+// Locks `<path>.lock` until the returned file is closed
+//
+// The counter file itself is replaced on every write, so it cannot hold the lock.
+fn lock(path: &Path) -> Result<fs::File> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(sibling(path, "lock"))?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(file)
+}
+
+// Writes the state file atomically, so a reset during the write
+// never leaves a truncated file behind
+//
+// The temporary file is synced before it replaces the state file and the
+// directory is synced after, so the new state is on disk when this returns.
+fn store(path: &Path, content: &str) -> Result<()> {
+    let tmp = sibling(path, "tmp");
+    {
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Counter used to sign outgoing commands
+#[derive(Debug, Default)]
+pub struct Counter {
+    value: Mutex<u32>,
+    path: Option<PathBuf>,
+}
+
+impl Counter {
+    /// Creates a counter, persisted in `path` if given
+    ///
+    /// A counter without a file starts at 0 again after a restart, so the service
+    /// rejects its commands until it passes the last counter the service accepted.
+    pub fn new(path: Option<PathBuf>) -> Result<Counter> {
+        let value = match &path {
+            Some(p) => load_counter(p)?,
+            None => 0,
+        };
+        Ok(Counter {
+            value: Mutex::new(value),
+            path,
+        })
+    }
+
+    /// Reads the path of the counter file from the service's config section
+    ///
+    /// Returns `Error::InvalidConfig` if `auth_counter` is not set.
+    pub fn from_config(config: &Config) -> Result<Counter> {
+        Counter::new(Some(path_from_config(config, "auth_counter")?))
+    }
+
+    /// Returns the next counter value and persists it before it is used
+    ///
+    /// The file is read again under a lock, as other clients may share it.
+    pub fn next(&self) -> Result<u32> {
+        let mut value = self.value.lock().map_err(|_| Error::PoisonedMutex)?;
+        let _lock = match &self.path {
+            Some(p) => {
+                let lock = lock(p)?;
+                *value = load_counter(p)?;
+                Some(lock)
+            }
+            None => None,
+        };
+        let next = value.checked_add(1).ok_or(Error::Replay)?;
+        if let Some(p) = &self.path {
+            store(p, &next.to_string())?;
+        }
+        *value = next;
+        Ok(next)
+    }
+}
+
+/// Last accepted counter per key
+#[derive(Debug, Default)]
+pub struct ReplayWindow {
+    last: Mutex<HashMap<u8, u32>>,
+    window: Option<u32>,
+    path: Option<PathBuf>,
+}
+
+impl ReplayWindow {
+    /// Creates a replay window, persisted in `path` if given
+    pub fn new(window: Option<u32>, path: Option<PathBuf>) -> Result<ReplayWindow> {
+        let mut last = HashMap::new();
+        if let Some(p) = path.as_ref().filter(|p| p.exists()) {
+            for line in fs::read_to_string(p)?.lines() {
+                let mut fields = line.split_whitespace().map(|f| f.parse::<u32>());
+                match (fields.next(), fields.next()) {
+                    (Some(Ok(id)), Some(Ok(counter))) if id <= u8::MAX as u32 => {
+                        last.insert(id as u8, counter);
+                    }
+                    _ => return Err(Error::InvalidConfig(p.display().to_string())),
+                }
+            }
+        }
+        Ok(ReplayWindow {
+            last: Mutex::new(last),
+            window,
+            path,
+        })
+    }
+
+    /// Reads window size and state file from the service's config section
+    ///
+    /// Returns `Error::InvalidConfig` if `replay_state` is not set.
+    pub fn from_config(config: &Config) -> Result<ReplayWindow> {
+        let window = match config.get("replay_window").map(|v| v.as_integer()) {
+            Some(Some(w)) if w > 0 && w <= u32::MAX as i64 => Some(w as u32),
+            Some(_) => return Err(Error::InvalidConfig("replay_window".to_string())),
+            None => None,
+        };
+        ReplayWindow::new(window, Some(path_from_config(config, "replay_state")?))
+    }
+
+    /// Accepts `counter` of key `id` if it lies within the window
+    ///
+    /// # Errors
+    ///
+    /// `Error::Replay` if `counter` is at or below the last accepted counter,
+    /// or more than the window size ahead of it.
+    pub fn check(&self, id: u8, counter: u32) -> Result<()> {
+        let mut last = self.last.lock().map_err(|_| Error::PoisonedMutex)?;
+        let prev = last.get(&id).copied().unwrap_or(0);
+        if counter <= prev {
+            return Err(Error::Replay);
+        }
+        if let Some(w) = self.window {
+            if counter - prev > w {
+                return Err(Error::Replay);
+            }
+        }
+        // persist before accepting, a command must never be accepted twice
+        let mut next = last.clone();
+        next.insert(id, counter);
+        if let Some(p) = &self.path {
+            let state: Vec<String> = next.iter().map(|(k, v)| format!("{} {}", k, v)).collect();
+            store(p, &state.join("\n"))?;
+        }
+        *last = next;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cubeos-replay-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn window_edges() {
+        let replay = ReplayWindow::new(Some(10), None).unwrap();
+        assert_eq!(replay.check(1, 0), Err(Error::Replay));
+        assert_eq!(replay.check(1, 11), Err(Error::Replay));
+        assert_eq!(replay.check(1, 10), Ok(()));
+        assert_eq!(replay.check(1, 10), Err(Error::Replay));
+        assert_eq!(replay.check(1, 9), Err(Error::Replay));
+        assert_eq!(replay.check(1, 20), Ok(()));
+        assert_eq!(replay.check(1, 31), Err(Error::Replay));
+        // every key has its own window
+        assert_eq!(replay.check(2, 1), Ok(()));
+        let unlimited = ReplayWindow::new(None, None).unwrap();
+        assert_eq!(unlimited.check(1, u32::MAX), Ok(()));
+        assert_eq!(unlimited.check(1, u32::MAX), Err(Error::Replay));
+    }
+
+    #[test]
+    fn persistence() {
+        let dir = dir("persistence");
+        let state = dir.join("state");
+        let replay = ReplayWindow::new(None, Some(state.clone())).unwrap();
+        replay.check(1, 5).unwrap();
+        replay.check(2, 7).unwrap();
+        drop(replay);
+        let replay = ReplayWindow::new(None, Some(state.clone())).unwrap();
+        assert_eq!(replay.check(1, 5), Err(Error::Replay));
+        assert_eq!(replay.check(2, 7), Err(Error::Replay));
+        assert_eq!(replay.check(1, 6), Ok(()));
+
+        let path = dir.join("counter");
+        let counter = Counter::new(Some(path.clone())).unwrap();
+        assert_eq!(counter.next(), Ok(1));
+        assert_eq!(counter.next(), Ok(2));
+        drop(counter);
+        assert_eq!(Counter::new(Some(path)).unwrap().next(), Ok(3));
+
+        fs::write(&state, "x").unwrap();
+        assert!(ReplayWindow::new(None, Some(state)).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shared_counter_file() {
+        let dir = dir("shared");
+        let path = dir.join("counter");
+        let a = std::sync::Arc::new(Counter::new(Some(path.clone())).unwrap());
+        let b = std::sync::Arc::new(Counter::new(Some(path)).unwrap());
+        let threads: Vec<_> = [a, b]
+            .iter()
+            .map(|c| {
+                let c = c.clone();
+                std::thread::spawn(move || (0..50).map(|_| c.next().unwrap()).collect::<Vec<u32>>())
+            })
+            .collect();
+        let mut all: Vec<u32> = threads.into_iter().flat_map(|t| t.join().unwrap()).collect();
+        all.sort_unstable();
+        assert_eq!(all, (1..=100).collect::<Vec<u32>>());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn side_files() {
+        let dir = dir("side");
+        let counter_path = dir.join("svc.counter");
+        let state_path = dir.join("svc.replay");
+        let counter = Counter::new(Some(counter_path.clone())).unwrap();
+        let replay = ReplayWindow::new(None, Some(state_path.clone())).unwrap();
+        assert_eq!(counter.next(), Ok(1));
+        replay.check(1, 9).unwrap();
+        assert_eq!(counter.next(), Ok(2));
+        // lock and temporary files keep the extension of their state file
+        assert!(dir.join("svc.counter.lock").exists());
+        assert!(!dir.join("svc.lock").exists());
+        assert!(!dir.join("svc.counter.tmp").exists() && !dir.join("svc.replay.tmp").exists());
+        assert_eq!(fs::read_to_string(&counter_path).unwrap(), "2");
+        assert_eq!(fs::read_to_string(&state_path).unwrap(), "1 9");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn state_files_required() {
+        let config = Config::new_from_str("svc", "[svc]\nauth_key_id = 1\n").unwrap();
+        assert_eq!(Counter::from_config(&config).err(), Some(Error::InvalidConfig("auth_counter".to_string())));
+        assert_eq!(ReplayWindow::from_config(&config).err(), Some(Error::InvalidConfig("replay_state".to_string())));
+    }
+}
@@ -0,0 +1,171 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Contributed by: Patrick Oppel (patrick.oppel94@gmail.com)
+//
+
+// Command-ID macro of the asynchronous service
+//
+// Same grammar as `udp_service_macro!`, whose parser it uses.
+// The commands are `async fn`s of the subsystem. A command that exceeds
+// its declared timeout is cancelled and replies `Error::TimedOut`.
+#[macro_export]
+macro_rules! async_service_macro {
+    (
+        use $error: ty;
+        impl $sub: ty {
+            $($entries: tt)*
+        }
+    ) => {
+        $crate::udp_service_macro!{@parse [async; $error; $sub] [] $($entries)*}
+    };
+    (
+        use $error: ty;
+        $krate: tt ::$strukt: tt {
+            $($entries: tt)*
+        }
+    ) => {
+        $crate::udp_service_macro!{@parse [async; $error; crate::$krate::$strukt] [] $($entries)*}
+    };
+    (@generate [$error: ty; $sub: ty] [$(
+        {$kind: ident $lock: ident $type: ident ($($id: literal)?) $func: tt [$($arg: tt: $cmd: ty),*] ($($timeout: literal)?) $rep: ty}
+    )*]) => {
+        use cubeos_service::{command_id,Frame};
+        use std::str::FromStr;
+        use log::debug;
+        type Subsystem = $sub;
+
+        command_id!{
+            $($type $(= $id)?,)*
+        }
+
+        // hash of the command table, replied to the built-in Interface command
+        pub const INTERFACE_HASH: u64 = cubeos_service::interface_hash(&[$(
+            cubeos_service::command_hash(stringify!($type), CommandID::$type.id(), &[$(stringify!($cmd)),*], stringify!($rep)),
+        )*]);
+
+        // UDP handler function running on the asynchronous service
+        // takes incoming msg and parses it into CommandID and Command for msg handling
+        // functions taking `&self` lock the subsystem for reading, `&mut self` for writing
+        // errors are recorded as the last error of the service
+        pub fn udp_handler(
+            sub: std::sync::Arc<cubeos_service::tokio::sync::RwLock<Box<Subsystem>>>,
+            msg: Vec<u8>,
+        ) -> cubeos_service::BoxFuture<CubeOSResult<Vec<u8>>> {
+            Box::pin(async move {
+                #[allow(unused_imports)]
+                use cubeos_service::{Builtin,LastBuiltin,LastDefault};
+                debug!("Message: {:?}",msg);
+
+                let result = udp_dispatch(&sub, &msg).await;
+                if let Err(e) = &result {
+                    let s = sub.read().await;
+                    (&&Builtin(&**s)).builtin_set_last_err(e.clone());
+                }
+                result
+            })
+        }
+
+        // description of all commands of the service
+        pub fn describe() -> CubeOSResult<Vec<cubeos_service::CommandInfo>> {
+            let mut commands = cubeos_service::builtin_commands();
+            $(commands.push(cubeos_service::CommandInfo::new(
+                stringify!($type),
+                u16::try_from(CommandID::$type)?,
+                $crate::command_kind!($kind),
+                &[$((stringify!($arg), stringify!($cmd))),*],
+                stringify!($rep),
+            ));)*
+            Ok(commands)
+        }
+
+        async fn udp_dispatch(sub: &cubeos_service::tokio::sync::RwLock<Box<Subsystem>>, msg: &Vec<u8>) -> CubeOSResult<Vec<u8>> {
+            #[allow(unused_imports)]
+            use cubeos_service::{Builtin,PingBuiltin,PingDefault,LastBuiltin,LastDefault};
+
+            // Validate frame and CommandID
+            let frame = Frame::parse(msg)?;
+            match CommandID::try_from(frame.id)? {
+                $(CommandID::$type => {
+                    let mut args = frame.args();
+                    $(let $arg = args.next::<$cmd>()?;)*
+                    args.finish()?;
+                    let call = async {
+                        $crate::async_subsystem_lock!($lock, sub, guard, s);
+                        (&&Builtin(&**s)).builtin_set_last_cmd(msg.clone());
+                        Subsystem::$func(s $(,$arg)*).await.map_err(CubeOSError::from)
+                    };
+                    // the timeout includes waiting for the lock
+                    $(let call = async {
+                        cubeos_service::tokio::time::timeout(std::time::Duration::from_millis($timeout), call)
+                            .await
+                            .unwrap_or(Err(CubeOSError::TimedOut))
+                    };)?
+                    let x = call.await?;
+                    let r = Frame::encode(<u16>::try_from(CommandID::$type)?, frame.seq, &bincode::serialize(&x)?)?;
+                    debug!("Reply: {:?}",r);
+                    Ok(r)
+                },)*
+                // built-in commands
+                CommandID::Ping => {
+                    frame.data::<()>()?;
+                    $crate::async_subsystem_lock!(query, sub, guard, s);
+                    let x = (&&Builtin(&**s)).builtin_ping()?;
+                    Frame::encode(cubeos_service::PING_ID, frame.seq, &bincode::serialize(&x)?)
+                },
+                CommandID::LastCmd => {
+                    frame.data::<()>()?;
+                    $crate::async_subsystem_lock!(query, sub, guard, s);
+                    let x = (&&Builtin(&**s)).builtin_get_last_cmd()?;
+                    Frame::encode(cubeos_service::LAST_CMD_ID, frame.seq, &bincode::serialize(&x)?)
+                },
+                CommandID::LastErr => {
+                    frame.data::<()>()?;
+                    $crate::async_subsystem_lock!(query, sub, guard, s);
+                    let x = (&&Builtin(&**s)).builtin_get_last_err()?;
+                    Frame::encode(cubeos_service::LAST_ERR_ID, frame.seq, &bincode::serialize(&x)?)
+                },
+                CommandID::LastHistory => {
+                    frame.data::<()>()?;
+                    $crate::async_subsystem_lock!(query, sub, guard, s);
+                    let x = (&&Builtin(&**s)).builtin_get_last_history()?;
+                    Frame::encode(cubeos_service::LAST_HISTORY_ID, frame.seq, &bincode::serialize(&x)?)
+                },
+                CommandID::Describe => {
+                    frame.data::<()>()?;
+                    Frame::encode(cubeos_service::DESCRIBE_ID, frame.seq, &bincode::serialize(&describe()?)?)
+                },
+                CommandID::Interface => {
+                    frame.data::<()>()?;
+                    Frame::encode(cubeos_service::INTERFACE_ID, frame.seq, &bincode::serialize(&INTERFACE_HASH)?)
+                },
+            }
+        }
+    };
+}
+
+// Locks the tokio RwLock of the subsystem for a query or mutation
+// and binds the guard and the reference to the subsystem
+#[macro_export]
+macro_rules! async_subsystem_lock {
+    (query, $lock: expr, $guard: ident, $sub: ident) => {
+        let $guard = $lock.read().await;
+        let $sub = &*$guard;
+    };
+    (mutation, $lock: expr, $guard: ident, $sub: ident) => {
+        let mut $guard = $lock.write().await;
+        let $sub = &mut *$guard;
+    };
+}
@@ -0,0 +1,414 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Contributed by: Patrick Oppel (patrick.oppel94@gmail.com)
+//
+
+// Asynchronous UDP service
+//
+// Same protocol and config section as the `udp` service, on a tokio `UdpSocket`.
+// Every request is handled in its own task. `workers` limits the number of
+// commands executed at the same time, `queue_depth` the number of requests
+// waiting for them. A command exceeding its timeout is cancelled by dropping
+// its future, so unlike a stuck worker thread it does not hold the subsystem.
+
+use super::dedup::{Lookup, ReplyCache};
+use super::udp::{handle_err, pool_from_config, shutdown_timeout_from_config, ShutdownFn};
+use super::watchdog::command_timeout_from_config;
+use crate::address::Address;
+use crate::codec::Codec;
+use crate::error::*;
+use crate::frame::Frame;
+use crate::builtin::LAST_HISTORY_LEN;
+use crate::last::{LastTracker, Scope, TASK_SCOPE};
+use kubos_system::Config;
+use log::{debug, info};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, OwnedSemaphorePermit, RwLock, Semaphore};
+use tokio::task::{JoinHandle, JoinSet};
+
+/// Future returned by an asynchronous handler
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send + 'static>>;
+
+/// Type definition for an asynchronous "UDP" server pointer
+///
+/// The handler locks the subsystem itself, for reading or writing depending on the command.
+pub type AsyncUdpFn<T> = dyn Fn(Arc<RwLock<T>>, Vec<u8>) -> BoxFuture<Result<Vec<u8>>> + Send + Sync + 'static;
+
+/// Asynchronous hardware service
+///
+/// ```rust,ignore
+/// #[tokio::main]
+/// async fn main() {
+///     let config = Config::new("example-service").unwrap();
+///     let sub = Box::new(Subsystem::new());
+///     Service::new(config, sub, Some(Arc::new(udp_handler))).start().await;
+/// }
+/// ```
+pub struct Service<T: Send + Sync + 'static> {
+    config: Config,
+    subsystem: Arc<RwLock<T>>,
+    /// Recent commands and errors, if the subsystem does not implement `Last`
+    last: Arc<LastTracker>,
+    /// Function pointer to a function that defines how to handle UDP requests
+    udp_handler: Option<Arc<AsyncUdpFn<T>>>,
+    /// Function called with the subsystem on shutdown
+    on_shutdown: Option<Arc<ShutdownFn<T>>>,
+}
+
+impl<T: Send + Sync + 'static> Service<T> {
+    /// Creates a new service instance
+    ///
+    /// # Arguments
+    ///
+    /// `config` - The config section of the service
+    /// `subsystem` - An instance of the subsystem struct. This one instance will be used by all queries.
+    pub fn new(config: Config, subsystem: T, udp_handler: Option<Arc<AsyncUdpFn<T>>>) -> Self {
+        Service {
+            config,
+            subsystem: Arc::new(RwLock::new(subsystem)),
+            last: Arc::new(LastTracker::new(LAST_HISTORY_LEN)),
+            udp_handler,
+            on_shutdown: None,
+        }
+    }
+
+    /// Sets a function that is called with the subsystem when the service shuts down
+    pub fn on_shutdown(mut self, hook: Arc<ShutdownFn<T>>) -> Self {
+        self.on_shutdown = Some(hook);
+        self
+    }
+
+    /// Runs the service's UDP server until the process receives SIGTERM or SIGINT
+    ///
+    /// Uses the same config keys as the synchronous service. On shutdown, requests
+    /// still executed after `shutdown_timeout` are cancelled.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the config is invalid, the address cannot be bound,
+    /// or the signal handlers cannot be installed.
+    pub async fn start(self) {
+        let (tx, rx) = watch::channel(false);
+        let mut term = signal(SignalKind::terminate()).expect("couldn't register signal handler");
+        let mut int = signal(SignalKind::interrupt()).expect("couldn't register signal handler");
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = term.recv() => {}
+                _ = int.recv() => {}
+            }
+            let _ = tx.send(true);
+        });
+        let server = self.load().await.unwrap();
+        self.run(server, rx).await;
+    }
+
+    /// Starts the service's UDP server on a new task
+    ///
+    /// Unlike `start`, no signal handlers are installed.
+    /// The service runs until `ShutdownHandle::shutdown` is called.
+    pub async fn spawn(self) -> Result<ShutdownHandle> {
+        let server = self.load().await?;
+        let addr = server.socket.local_addr()?;
+        let (shutdown, rx) = watch::channel(false);
+        let task = tokio::spawn(self.run(server, rx));
+        Ok(ShutdownHandle { addr, shutdown, task })
+    }
+
+    // Reads the config and binds the socket
+    async fn load(&self) -> Result<Server> {
+        // other transports are only served by the threaded service
+        let addr = match Address::from_config(&self.config) {
+            Ok(Address::Ip(addr)) => addr,
+            Ok(addr) => {
+                log::error!("Unsupported transport: {}", addr);
+                return Err(Error::InvalidConfig("addr".to_string()));
+            }
+            Err(err) => {
+                log::error!("Failed to load service address: {:?}", err);
+                return Err(err);
+            }
+        };
+        let codec = Codec::from_config(&self.config).map_err(|err| {
+            log::error!("Failed to load codec: {:?}", err);
+            err
+        })?;
+        let cache = ReplyCache::from_config(&self.config).map_err(|err| {
+            log::error!("Failed to load duplicate window: {:?}", err);
+            err
+        })?;
+        let (workers, queue_depth) = pool_from_config(&self.config).map_err(|err| {
+            log::error!("Failed to load worker pool: {:?}", err);
+            err
+        })?;
+        let timeout = command_timeout_from_config(&self.config).map_err(|err| {
+            log::error!("Failed to load command timeout: {:?}", err);
+            err
+        })?;
+        let shutdown_timeout = shutdown_timeout_from_config(&self.config).map_err(|err| {
+            log::error!("Failed to load shutdown timeout: {:?}", err);
+            err
+        })?;
+        let socket = UdpSocket::bind(addr).await.map_err(|err| {
+            log::error!("Failed to bind {}: {:?}", addr, err);
+            err
+        })?;
+        info!("Listening on: {}", socket.local_addr()?);
+        Ok(Server {
+            socket: Arc::new(socket),
+            codec,
+            cache: Arc::new(Mutex::new(cache)),
+            workers,
+            queue_depth,
+            timeout,
+            shutdown_timeout,
+        })
+    }
+
+    async fn run(self, server: Server, mut shutdown: watch::Receiver<bool>) {
+        let Server { socket, codec, cache, workers, queue_depth, timeout, shutdown_timeout } = server;
+        let udp_handler = self.udp_handler.clone().unwrap();
+
+        // requests that are executed or wait to be executed
+        let admitted = Arc::new(Semaphore::new(workers + queue_depth));
+        // requests that are executed
+        let running = Arc::new(Semaphore::new(workers));
+        let mut tasks = JoinSet::new();
+
+        // loop for UDP handling
+        // listens for UDP messages on socket,
+        // validates them and executes each in its own task
+        // requests are decoded in order of arrival, so the replay window sees increasing counters
+        let mut buf = vec![0u8; u16::MAX as usize];
+        loop {
+            let (b, a) = tokio::select! {
+                // a dropped ShutdownHandle also stops the service
+                _ = shutdown.changed() => break,
+                // collect finished tasks
+                Some(_) = tasks.join_next(), if !tasks.is_empty() => continue,
+                r = socket.recv_from(&mut buf) => match r {
+                    Ok((n, a)) => (buf[..n].to_vec(), a),
+                    Err(e) => {
+                        debug!("Failed to receive: {:?}", e);
+                        continue;
+                    }
+                },
+            };
+            // answer retransmitted requests from the cache
+            // the lock is released before the reply is sent
+            let lookup = match cache.lock() {
+                Ok(mut c) => c.get(&Address::Ip(a), &b),
+                Err(_) => Lookup::New,
+            };
+            match lookup {
+                Lookup::Cached(x) => {
+                    debug!("Resend cached reply: {:?} to {:?}", &x, &a);
+                    send(&socket, &x, &a).await;
+                    continue;
+                }
+                Lookup::Pending => {
+                    debug!("Drop retransmission of pending request from {:?}", &a);
+                    continue;
+                }
+                Lookup::New => {}
+            }
+            // reject the request before it is checked against the replay window,
+            // so the client may retry it
+            let permit = match admitted.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    let seq = codec.decode_command(&b).ok().and_then(Frame::peek_seq).unwrap_or(0);
+                    match codec.encode_reply(handle_err(&Error::Busy, seq)) {
+                        Ok(x) => send(&socket, &x, &a).await,
+                        Err(e) => log::error!("Failed to encode reply: {:?}", e),
+                    }
+                    continue;
+                }
+            };
+            match codec.decode_request(&b).map(|f| f.to_vec()) {
+                Ok(f) => {
+                    if let Ok(mut cache) = cache.lock() {
+                        cache.begin(Address::Ip(a), b.clone());
+                    }
+                    tasks.spawn(execute(
+                        Request { request: b, frame: f, sender: a },
+                        Worker {
+                            handler: udp_handler.clone(),
+                            subsystem: self.subsystem.clone(),
+                            last: self.last.clone(),
+                            socket: socket.clone(),
+                            codec: codec.clone(),
+                            cache: cache.clone(),
+                            running: running.clone(),
+                            timeout,
+                        },
+                        permit,
+                    ));
+                }
+                // the client matches the error to its request by the sequence number
+                Err(e) => match codec.encode_reply(handle_err(&e, codec.peek_seq(&b).unwrap_or(0))) {
+                    Ok(x) => {
+                        debug!("Send: {:?} to {:?}", &x, &a);
+                        send(&socket, &x, &a).await;
+                    }
+                    Err(e) => log::error!("Failed to encode reply: {:?}", e),
+                },
+            }
+        }
+
+        // shutdown
+        // stop accepting requests, cancel the requests that do not finish in time
+        info!("Shutting down");
+        let drain = async {
+            while tasks.join_next().await.is_some() {}
+        };
+        if tokio::time::timeout(shutdown_timeout, drain).await.is_err() {
+            log::error!("Requests did not finish within {:?}, cancel {}", shutdown_timeout, tasks.len());
+            tasks.abort_all();
+            while tasks.join_next().await.is_some() {}
+        }
+
+        if let Some(hook) = &self.on_shutdown {
+            let mut s = self.subsystem.write().await;
+            if let Err(e) = hook(&mut s) {
+                log::error!("Shutdown hook failed: {:?}", e);
+            }
+        }
+        info!("Service stopped");
+    }
+}
+
+/// Handle to a service started with `Service::spawn`
+pub struct ShutdownHandle {
+    addr: SocketAddr,
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl ShutdownHandle {
+    /// Address the service is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stops the service and waits until it has shut down
+    pub async fn shutdown(self) -> Result<()> {
+        let _ = self.shutdown.send(true);
+        self.task.await.map_err(|_| Error::Failure("Service panicked".to_string()))
+    }
+}
+
+// Configuration and socket of a started service
+struct Server {
+    socket: Arc<UdpSocket>,
+    codec: Codec,
+    cache: Arc<Mutex<ReplyCache>>,
+    workers: usize,
+    queue_depth: usize,
+    timeout: Option<Duration>,
+    shutdown_timeout: Duration,
+}
+
+// Received request
+struct Request {
+    /// Request as received, used as key of the reply cache
+    request: Vec<u8>,
+    frame: Vec<u8>,
+    sender: SocketAddr,
+}
+
+// Everything a task needs to execute a request
+struct Worker<T: Send + Sync + 'static> {
+    handler: Arc<AsyncUdpFn<T>>,
+    subsystem: Arc<RwLock<T>>,
+    last: Arc<LastTracker>,
+    socket: Arc<UdpSocket>,
+    codec: Codec,
+    cache: Arc<Mutex<ReplyCache>>,
+    running: Arc<Semaphore>,
+    timeout: Option<Duration>,
+}
+
+// Executes a request and sends the reply
+//
+// `_admitted` is held until the reply is sent, so the request counts against the queue depth.
+async fn execute<T: Send + Sync + 'static>(req: Request, w: Worker<T>, _admitted: OwnedSemaphorePermit) {
+    let Request { request, frame, sender } = req;
+    let Worker { handler, subsystem, last, socket, codec, cache, running, timeout } = w;
+    let seq = Frame::peek_seq(&frame).unwrap_or(0);
+    // the semaphore is never closed
+    let _running = running.acquire_owned().await;
+    // the timeout starts when the command is executed
+    let call = TASK_SCOPE.scope(Scope { sender: Some(sender), last }, handler(subsystem, frame));
+    let result = match timeout {
+        Some(t) => tokio::time::timeout(t, call).await.unwrap_or_else(|_| {
+            log::error!("Request from {:?} exceeded {:?}, cancelled", &sender, t);
+            Err(Error::TimedOut)
+        }),
+        None => call.await,
+    };
+    let reply = result
+        .and_then(|x| codec.encode_reply(x))
+        .or_else(|e| codec.encode_reply(handle_err(&e, seq)));
+    match reply {
+        Ok(x) => {
+            debug!("Send: {:?} to {:?}", &x, &sender);
+            send(&socket, &x, &sender).await;
+            if let Ok(mut cache) = cache.lock() {
+                cache.insert(&Address::Ip(sender), &request, x);
+            }
+        }
+        Err(e) => log::error!("Failed to encode reply: {:?}", e),
+    }
+}
+
+async fn send(socket: &UdpSocket, msg: &[u8], to: &SocketAddr) {
+    if let Err(e) = socket.send_to(msg, to).await {
+        log::error!("Failed to send to {:?}: {:?}", to, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Checksum;
+
+    fn echo(_: Arc<RwLock<u8>>, frame: Vec<u8>) -> BoxFuture<Result<Vec<u8>>> {
+        Box::pin(async move { Ok(frame) })
+    }
+
+    #[tokio::test]
+    async fn rejection_carries_seq() {
+        let cfg = "[svc]\nchecksum = \"crc16\"\n[svc.addr]\nip = \"127.0.0.1\"\nport = 0\n";
+        let h = Service::new(Config::new_from_str("svc", cfg).unwrap(), 0u8, Some(Arc::new(echo))).spawn().await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(h.local_addr()).await.unwrap();
+        let codec = Codec { checksum: Checksum::Crc16, ..Codec::default() };
+        let mut request = codec.encode(Frame::encode(7, 0x1234, &[1, 2, 3]).unwrap()).unwrap();
+        *request.last_mut().unwrap() ^= 1;
+        socket.send(&request).await.unwrap();
+        let mut buf = vec![0u8; u16::MAX as usize];
+        let n = tokio::time::timeout(Duration::from_secs(1), socket.recv(&mut buf)).await.unwrap().unwrap();
+        let frame = Frame::parse(codec.decode(&buf[..n]).unwrap()).unwrap();
+        assert_eq!((frame.error(), frame.seq), (Error::Checksum, 0x1234));
+        h.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,308 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Contributed by: Patrick Oppel (patrick.oppel94@gmail.com)
+//
+
+// CSP transport
+//
+// A `CspNode` is a CSP address on an interface, like a KISS serial link.
+// Services bind a port of the node and receive the Command frames carried as
+// payload of the packets to that port, clients bind a free port above
+// `CSP_MAX_BIND_PORT` for the replies, as libcsp does for outgoing connections.
+// The node's router thread reads the interface and hands every packet to the
+// port it is addressed to, so several services and clients share one interface.
+//
+// ```toml,ignore
+// [service-name.csp]
+// address = 5            # CSP address of the node
+// port = 10              # port of the service
+// priority = "normal"    # critical, high, normal or low, default normal
+// crc = true             # append CRC-32C, default true
+//
+// [service-name.uart]    # the interface, KISS framing by default
+// path = "/dev/ttyS1"
+// ```
+
+use super::serial::SerialTransport;
+use super::transport::Transport;
+use crate::address::Address;
+use crate::csp::*;
+use crate::error::*;
+use log::debug;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Largest port a service can bind, higher ports are used by clients
+pub const CSP_MAX_BIND_PORT: u8 = 31;
+
+// Interval in which the router checks whether the node was dropped
+const ROUTER_POLL: Duration = Duration::from_millis(50);
+
+type Ports = Arc<Mutex<HashMap<u8, mpsc::Sender<(CspHeader, Vec<u8>)>>>>;
+
+/// Link on which CSP packets are sent and received
+pub trait CspInterface: Send + Sync {
+    /// Sends an encoded packet
+    fn send(&self, packet: &[u8]) -> Result<()>;
+
+    /// Waits up to `timeout` for the next packet
+    fn recv(&self, timeout: Duration) -> Result<Option<Vec<u8>>>;
+}
+
+/// Serial link with KISS framing, like the KISS interface of libcsp
+impl<S: Read + Write + Send> CspInterface for SerialTransport<S> {
+    fn send(&self, packet: &[u8]) -> Result<()> {
+        Transport::send(self, packet, &Transport::local_addr(self)?)
+    }
+
+    fn recv(&self, timeout: Duration) -> Result<Option<Vec<u8>>> {
+        Ok(Transport::recv(self, timeout)?.map(|(packet, _)| packet))
+    }
+}
+
+/// Interface that delivers every packet back to its own node
+///
+/// Services and clients on one node talk to each other without hardware.
+pub struct CspLoopback {
+    tx: Mutex<mpsc::Sender<Vec<u8>>>,
+    rx: Mutex<mpsc::Receiver<Vec<u8>>>,
+}
+
+impl CspLoopback {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel();
+        CspLoopback { tx: Mutex::new(tx), rx: Mutex::new(rx) }
+    }
+}
+
+impl Default for CspLoopback {
+    fn default() -> Self {
+        CspLoopback::new()
+    }
+}
+
+impl CspInterface for CspLoopback {
+    fn send(&self, packet: &[u8]) -> Result<()> {
+        self.tx
+            .lock()
+            .map_err(|_| Error::PoisonedMutex)?
+            .send(packet.to_vec())
+            .map_err(|_| Error::from(std::io::ErrorKind::NotConnected))
+    }
+
+    fn recv(&self, timeout: Duration) -> Result<Option<Vec<u8>>> {
+        let rx = self.rx.lock().map_err(|_| Error::PoisonedMutex)?;
+        Ok(rx.recv_timeout(timeout).ok())
+    }
+}
+
+/// CSP address on an interface
+///
+/// ```rust,ignore
+/// let node = Arc::new(CspNode::new(Arc::new(CspLoopback::new()), 5)?);
+/// Service::new(config, sub, Some(Arc::new(udp_handler)))
+///     .transport(Arc::new(node.bind(10)?))
+///     .spawn()?;
+/// let client = Client::<CommandID>::builder().target_csp(&node, 5, 10).build()?;
+/// ```
+pub struct CspNode {
+    address: u8,
+    iface: Arc<dyn CspInterface>,
+    ports: Ports,
+    priority: CspPriority,
+    crc: bool,
+    closed: Arc<AtomicBool>,
+}
+
+impl CspNode {
+    /// Starts the router of the node with `address` on `iface`
+    ///
+    /// Packets are sent with normal priority and a CRC.
+    pub fn new(iface: Arc<dyn CspInterface>, address: u8) -> Result<Self> {
+        if address > CSP_MAX_ADDRESS {
+            return Err(Error::from(std::io::ErrorKind::InvalidInput));
+        }
+        let ports = Ports::default();
+        let closed = Arc::new(AtomicBool::new(false));
+        {
+            let iface = iface.clone();
+            let ports = ports.clone();
+            let closed = closed.clone();
+            thread::Builder::new()
+                .name(format!("csp-{}", address))
+                .spawn(move || route(iface, address, ports, closed))?;
+        }
+        Ok(CspNode {
+            address,
+            iface,
+            ports,
+            priority: CspPriority::Normal,
+            crc: true,
+            closed,
+        })
+    }
+
+    /// Priority of the packets sent by the node
+    pub fn priority(mut self, priority: CspPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Whether the packets sent by the node end with a CRC-32C
+    pub fn crc(mut self, crc: bool) -> Self {
+        self.crc = crc;
+        self
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// Binds a port up to `CSP_MAX_BIND_PORT` for a service
+    pub fn bind(self: &Arc<Self>, port: u8) -> Result<CspTransport> {
+        if port > CSP_MAX_BIND_PORT {
+            return Err(Error::from(std::io::ErrorKind::InvalidInput));
+        }
+        let mut ports = self.ports.lock().map_err(|_| Error::PoisonedMutex)?;
+        if ports.contains_key(&port) {
+            return Err(Error::from(std::io::ErrorKind::AddrInUse));
+        }
+        Ok(self.open(&mut ports, port))
+    }
+
+    /// Binds a free port above `CSP_MAX_BIND_PORT` for a client
+    pub fn bind_any(self: &Arc<Self>) -> Result<CspTransport> {
+        let mut ports = self.ports.lock().map_err(|_| Error::PoisonedMutex)?;
+        match (CSP_MAX_BIND_PORT + 1..=CSP_MAX_PORT).find(|p| !ports.contains_key(p)) {
+            Some(port) => Ok(self.open(&mut ports, port)),
+            None => Err(Error::from(std::io::ErrorKind::AddrInUse)),
+        }
+    }
+
+    fn open(self: &Arc<Self>, ports: &mut HashMap<u8, mpsc::Sender<(CspHeader, Vec<u8>)>>, port: u8) -> CspTransport {
+        let (tx, rx) = mpsc::channel();
+        ports.insert(port, tx);
+        CspTransport {
+            node: self.clone(),
+            port,
+            packets: Mutex::new(rx),
+        }
+    }
+}
+
+impl Drop for CspNode {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+// Hands the packets to this node to the bound ports
+fn route(iface: Arc<dyn CspInterface>, address: u8, ports: Ports, closed: Arc<AtomicBool>) {
+    while !closed.load(Ordering::SeqCst) {
+        let packet = match iface.recv(ROUTER_POLL) {
+            Ok(Some(packet)) => packet,
+            Ok(None) => continue,
+            Err(e) => {
+                log::error!("Failed to receive CSP packet: {:?}", e);
+                thread::sleep(ROUTER_POLL);
+                continue;
+            }
+        };
+        let (header, payload) = match decode_csp(&packet) {
+            Ok(p) => p,
+            Err(e) => {
+                debug!("Drop CSP packet: {:?}", e);
+                continue;
+            }
+        };
+        if header.destination != address {
+            continue;
+        }
+        match ports.lock().ok().and_then(|p| p.get(&header.destination_port).cloned()) {
+            Some(port) => {
+                let _ = port.send((header, payload.to_vec()));
+            }
+            None => debug!("Drop CSP packet to unbound port {}", header.destination_port),
+        }
+    }
+}
+
+/// Port of a `CspNode`
+///
+/// Requests come from `Address::Csp` with the address and port of the client.
+/// The port is released when the transport is dropped.
+pub struct CspTransport {
+    node: Arc<CspNode>,
+    port: u8,
+    packets: Mutex<mpsc::Receiver<(CspHeader, Vec<u8>)>>,
+}
+
+impl CspTransport {
+    pub fn port(&self) -> u8 {
+        self.port
+    }
+}
+
+impl Transport for CspTransport {
+    fn recv(&self, timeout: Duration) -> Result<Option<(Vec<u8>, Address)>> {
+        let packets = self.packets.lock().map_err(|_| Error::PoisonedMutex)?;
+        match packets.recv_timeout(timeout) {
+            Ok((header, payload)) => Ok(Some((
+                payload,
+                Address::Csp {
+                    address: header.source,
+                    port: header.source_port,
+                },
+            ))),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn send(&self, msg: &[u8], to: &Address) -> Result<()> {
+        let (address, port) = match to {
+            Address::Csp { address, port } => (*address, *port),
+            _ => return Err(Error::from(std::io::ErrorKind::InvalidInput)),
+        };
+        let header = CspHeader {
+            priority: self.node.priority,
+            source: self.node.address,
+            destination: address,
+            destination_port: port,
+            source_port: self.port,
+            crc: self.node.crc,
+        };
+        self.node.iface.send(&header.encode(msg))
+    }
+
+    fn local_addr(&self) -> Result<Address> {
+        Ok(Address::Csp {
+            address: self.node.address,
+            port: self.port,
+        })
+    }
+}
+
+impl Drop for CspTransport {
+    fn drop(&mut self) {
+        if let Ok(mut ports) = self.node.ports.lock() {
+            ports.remove(&self.port);
+        }
+    }
+}
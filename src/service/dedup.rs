@@ -0,0 +1,136 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Contributed by: Patrick Oppel (patrick.oppel94@gmail.com)
+//
+
+// Suppression of retransmitted commands
+//
+// A client that does not receive a reply resends the same message,
+// i.e. with the same sequence number and counter, from the same socket.
+// The service keeps the replies to the most recent requests and answers
+// such a retransmission from the cache instead of executing it again.
+// A retransmission of a request that is still being executed is dropped,
+// the client receives the reply once the request has finished.
+//
+// ```toml,ignore
+// [service-name]
+// duplicate_window = 16   # number of cached replies, 0 disables the cache
+// duplicate_timeout = 60  # seconds a reply is kept
+// ```
+
+use crate::address::Address;
+use crate::error::*;
+use kubos_system::Config;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+const DEFAULT_WINDOW: usize = 16;
+const DEFAULT_TIMEOUT: u64 = 60;
+
+struct Entry {
+    sender: Address,
+    request: Vec<u8>,
+    // None while the request is executed
+    reply: Option<Vec<u8>>,
+    time: Instant,
+}
+
+/// Result of looking up a received request
+pub enum Lookup {
+    /// The request was not seen before
+    New,
+    /// The request is still being executed
+    Pending,
+    /// The reply to the already executed request
+    Cached(Vec<u8>),
+}
+
+/// Window of recently executed requests and their replies
+pub struct ReplyCache {
+    window: usize,
+    timeout: Duration,
+    entries: VecDeque<Entry>,
+}
+
+impl ReplyCache {
+    pub fn new(window: usize, timeout: Duration) -> Self {
+        ReplyCache {
+            window,
+            timeout,
+            entries: VecDeque::with_capacity(window),
+        }
+    }
+
+    /// Reads window size and timeout from the service's config section
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let window = match config.get("duplicate_window").map(|v| v.as_integer()) {
+            Some(Some(w)) if w >= 0 => w as usize,
+            Some(_) => return Err(Error::InvalidConfig("duplicate_window".to_string())),
+            None => DEFAULT_WINDOW,
+        };
+        let timeout = match config.get("duplicate_timeout").map(|v| v.as_integer()) {
+            Some(Some(t)) if t >= 0 => t as u64,
+            Some(_) => return Err(Error::InvalidConfig("duplicate_timeout".to_string())),
+            None => DEFAULT_TIMEOUT,
+        };
+        Ok(ReplyCache::new(window, Duration::from_secs(timeout)))
+    }
+
+    /// Looks up `request` from `sender` among the recent requests
+    pub fn get(&mut self, sender: &Address, request: &[u8]) -> Lookup {
+        let timeout = self.timeout;
+        // pending entries are kept until the request has finished
+        self.entries
+            .retain(|e| e.reply.is_none() || e.time.elapsed() < timeout);
+        match self
+            .entries
+            .iter()
+            .find(|e| e.sender == *sender && e.request == request)
+        {
+            Some(Entry { reply: Some(r), .. }) => Lookup::Cached(r.clone()),
+            Some(_) => Lookup::Pending,
+            None => Lookup::New,
+        }
+    }
+
+    /// Marks a request as being executed
+    pub fn begin(&mut self, sender: Address, request: Vec<u8>) {
+        if self.window == 0 {
+            return;
+        }
+        if self.entries.len() >= self.window {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Entry {
+            sender,
+            request,
+            reply: None,
+            time: Instant::now(),
+        });
+    }
+
+    /// Stores the reply to an executed request
+    pub fn insert(&mut self, sender: &Address, request: &[u8], reply: Vec<u8>) {
+        if let Some(e) = self
+            .entries
+            .iter_mut()
+            .find(|e| e.sender == *sender && e.request == request && e.reply.is_none())
+        {
+            e.reply = Some(reply);
+            e.time = Instant::now();
+        }
+    }
+}
@@ -16,19 +16,68 @@
 // Contributed by: Patrick Oppel (patrick.oppel94@gmail.com)
 //
 
-// Define which implementation of the Service and service-macro!
-// are used depending on the selected feature
+// The flight service (`udp` feature), its asynchronous variant (`async` feature)
+// and the ground terminal (`terminal` feature) are separate modules and can be built together.
+// Enabling a feature only adds its module: `Service` and `Context` at the root are always
+// the flight service, the terminal is reached through `terminal::`.
+// `service_macro!` takes the implementation as its first argument, so flight and ground
+// binaries of one workspace share one build of cubeos-service.
 
-#[cfg(any(not(any(feature = "terminal", feature = "app")), all(feature = "app", feature = "service")))]
-mod udp;
-#[cfg(any(not(any(feature = "terminal", feature = "app")), all(feature = "app", feature = "service")))]
+#[cfg(feature = "udp")]
+pub mod udp;
+#[cfg(feature = "udp")]
 mod udp_macro;
-#[cfg(any(not(any(feature = "terminal", feature = "app")), all(feature = "app", feature = "service")))]
-pub use udp::{Context,Service};
+#[cfg(feature = "udp")]
+mod dedup;
+#[cfg(feature = "udp")]
+mod watchdog;
+#[cfg(feature = "udp")]
+mod transport;
+#[cfg(feature = "udp")]
+mod serial;
+#[cfg(feature = "udp")]
+mod tcp;
+#[cfg(feature = "udp")]
+mod csp;
+#[cfg(feature = "udp")]
+pub use udp::{Context,Service,ShutdownHandle,ShutdownFn};
+#[cfg(feature = "udp")]
+pub use transport::{transport_from_config,Transport,UdpTransport,UnixTransport};
+#[cfg(feature = "udp")]
+pub use serial::{SerialTransport,SERIAL_POLL};
+#[cfg(feature = "udp")]
+pub use tcp::{TcpTransport,DEFAULT_IDLE_TIMEOUT,DEFAULT_MAX_CONNECTIONS};
+#[cfg(feature = "udp")]
+pub use csp::{CspInterface,CspLoopback,CspNode,CspTransport,CSP_MAX_BIND_PORT};
+#[cfg(feature = "udp")]
+pub use watchdog::arm_watchdog;
+
+#[cfg(feature = "async")]
+pub mod async_udp;
+#[cfg(feature = "async")]
+mod async_macro;
+#[cfg(feature = "async")]
+pub use async_udp::{AsyncUdpFn,BoxFuture};
 
 #[cfg(feature = "terminal")]
-mod terminal;
+pub mod terminal;
 #[cfg(feature = "terminal")]
 mod terminal_macro;
-#[cfg(feature = "terminal")]
-pub use terminal::{Context,Service,UdpPassthrough};
\ No newline at end of file
+
+// UDP handler of the flight service (`udp`), the asynchronous handler (`async`)
+// or the ground terminal (`terminal`), the UDP handler if no implementation is given
+#[macro_export]
+macro_rules! service_macro {
+    (udp; $($input: tt)*) => {
+        $crate::udp_service_macro!{$($input)*}
+    };
+    (async; $($input: tt)*) => {
+        $crate::async_service_macro!{$($input)*}
+    };
+    (terminal; $($input: tt)*) => {
+        $crate::terminal_service_macro!{$($input)*}
+    };
+    (use $($input: tt)*) => {
+        $crate::udp_service_macro!{use $($input)*}
+    };
+}
@@ -0,0 +1,118 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Contributed by: Patrick Oppel (patrick.oppel94@gmail.com)
+//
+
+// Serial transport
+//
+// Messages are framed with SLIP or KISS on a point-to-point link,
+// e.g. RS-422 between a board and the OBC. All replies go to the other end of
+// the link. The port is shared by the receiving thread and the workers, reads
+// time out after `SERIAL_POLL` so replies wait at most that long for the port.
+
+use super::transport::Transport;
+use crate::address::Address;
+use crate::error::*;
+use crate::framing::{Deframer, Framing};
+use serial::SerialPort;
+use std::io::{Read, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Read timeout of the serial port
+pub const SERIAL_POLL: Duration = Duration::from_millis(10);
+
+/// Serial link with SLIP or KISS framing
+pub struct SerialTransport<S> {
+    port: Mutex<S>,
+    framing: Framing,
+    deframer: Mutex<Deframer>,
+    name: String,
+}
+
+impl SerialTransport<serial::SystemPort> {
+    /// Opens the serial device at `path` with 8N1 and no flow control
+    pub fn open(path: &str, baud: usize, framing: Framing) -> Result<Self> {
+        let mut port = serial::open(path)?;
+        port.reconfigure(&|s| {
+            s.set_baud_rate(serial::BaudRate::from_speed(baud))?;
+            s.set_char_size(serial::Bits8);
+            s.set_parity(serial::ParityNone);
+            s.set_stop_bits(serial::Stop1);
+            s.set_flow_control(serial::FlowNone);
+            Ok(())
+        })?;
+        port.set_timeout(SERIAL_POLL)?;
+        Ok(SerialTransport::new(port, framing, path))
+    }
+}
+
+impl<S: Read + Write + Send> SerialTransport<S> {
+    /// Uses a byte stream as serial link
+    ///
+    /// Reads of the stream should time out after a short interval like `SERIAL_POLL`,
+    /// as sending waits for a pending read.
+    pub fn new(port: S, framing: Framing, name: &str) -> Self {
+        SerialTransport {
+            port: Mutex::new(port),
+            framing,
+            deframer: Mutex::new(Deframer::new(framing)),
+            name: name.to_string(),
+        }
+    }
+}
+
+impl<S: Read + Write + Send> Transport for SerialTransport<S> {
+    fn recv(&self, timeout: Duration) -> Result<Option<(Vec<u8>, Address)>> {
+        let deadline = Instant::now() + timeout;
+        let mut buf = [0u8; 256];
+        loop {
+            if let Some(msg) = self.deframer.lock().map_err(|_| Error::PoisonedMutex)?.next_message() {
+                return Ok(Some((msg, Address::Serial(self.name.clone()))));
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+            // the deframer is not locked while waiting for the port, the bytes are
+            // pushed before the port is released so they stay in order
+            let n = {
+                let mut port = self.port.lock().map_err(|_| Error::PoisonedMutex)?;
+                let n = match port.read(&mut buf) {
+                    Ok(n) => n,
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => 0,
+                    Err(e) => return Err(e.into()),
+                };
+                self.deframer.lock().map_err(|_| Error::PoisonedMutex)?.push(&buf[..n]);
+                n
+            };
+            // a closed stream returns immediately
+            if n == 0 {
+                std::thread::sleep(SERIAL_POLL);
+            }
+        }
+    }
+
+    fn send(&self, msg: &[u8], _to: &Address) -> Result<()> {
+        let mut port = self.port.lock().map_err(|_| Error::PoisonedMutex)?;
+        port.write_all(&self.framing.encode(msg))?;
+        port.flush()?;
+        Ok(())
+    }
+
+    fn local_addr(&self) -> Result<Address> {
+        Ok(Address::Serial(self.name.clone()))
+    }
+}
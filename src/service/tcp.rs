@@ -0,0 +1,345 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Contributed by: Patrick Oppel (patrick.oppel94@gmail.com)
+//
+
+// TCP transport
+//
+// Messages are prefixed with their length, so replies are not limited by the
+// size of a UDP datagram. Clients keep their connection for many requests.
+// Every connection has a thread that reads its requests and queues them for the
+// service, the replies are written to the connection the request came from.
+// The peer address of the connection is the sender of its requests, so the
+// reply cache answers a retransmission on the same connection.
+//
+// The queue holds as many requests as the request queue of the service, a reader
+// waits for room before it reads the next request from its connection. Connections
+// beyond `max_connections` are closed when they are accepted. A connection is idle
+// while all its requests are answered and closed once it was idle for `idle_timeout`,
+// so a command slower than `idle_timeout` keeps its connection until the reply is sent.
+//
+// ```toml,ignore
+// [service-name.addr]
+// protocol = "tcp"
+// max_connections = 16   # default 16
+// idle_timeout = 60000   # milliseconds, default 60000
+// ```
+
+use super::transport::Transport;
+use super::udp::DEFAULT_QUEUE_DEPTH;
+use crate::address::Address;
+use crate::error::*;
+use crate::framing::{encode_prefixed, take_prefixed};
+use log::debug;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Interval in which the listener checks whether the transport was dropped
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+// Interval in which a reader checks whether its connection is idle
+const IDLE_POLL: Duration = Duration::from_millis(100);
+// Time a worker waits for a client that does not read its reply
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of connections a `TcpTransport` keeps open by default
+pub const DEFAULT_MAX_CONNECTIONS: usize = 16;
+
+/// Time without a request in flight after which a connection is closed by default
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+type Connections = Arc<Mutex<HashMap<SocketAddr, Arc<Connection>>>>;
+
+// Writer of a connection and its requests that wait for a reply
+struct Connection {
+    stream: Mutex<TcpStream>,
+    // requests without reply and the time the last one was answered
+    pending: Mutex<(usize, Instant)>,
+}
+
+impl Connection {
+    // A request was queued
+    fn begin(&self) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.0 += 1;
+        }
+    }
+
+    // A request was answered or dropped
+    fn finish(&self) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.0 = pending.0.saturating_sub(1);
+            pending.1 = Instant::now();
+        }
+    }
+
+    // Time since the last request was answered, zero while one is in flight
+    fn idle(&self) -> Duration {
+        match self.pending.lock() {
+            Ok(pending) if pending.0 == 0 => pending.1.elapsed(),
+            _ => Duration::from_secs(0),
+        }
+    }
+}
+
+/// TCP listener with length-prefixed messages
+///
+/// Open connections are closed when the transport is dropped.
+pub struct TcpTransport {
+    addr: SocketAddr,
+    requests: Mutex<mpsc::Receiver<(Vec<u8>, Address)>>,
+    connections: Connections,
+    closed: Arc<AtomicBool>,
+}
+
+impl TcpTransport {
+    /// Listens on `addr` with the default limits
+    pub fn bind(addr: SocketAddr) -> Result<Self> {
+        TcpTransport::bind_with_limits(addr, DEFAULT_QUEUE_DEPTH, DEFAULT_MAX_CONNECTIONS, DEFAULT_IDLE_TIMEOUT)
+    }
+
+    /// Listens on `addr`, queues up to `queue_depth` requests and keeps up to
+    /// `max_connections` connections open until they are idle for `idle_timeout`
+    pub fn bind_with_limits(addr: SocketAddr, queue_depth: usize, max_connections: usize, idle_timeout: Duration) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let (tx, rx) = mpsc::sync_channel(queue_depth);
+        let connections = Connections::default();
+        let closed = Arc::new(AtomicBool::new(false));
+        {
+            let limits = Limits { max_connections, idle_timeout };
+            let connections = connections.clone();
+            let closed = closed.clone();
+            thread::Builder::new()
+                .name("tcp-listener".to_string())
+                .spawn(move || accept(listener, tx, limits, connections, closed))?;
+        }
+        Ok(TcpTransport {
+            addr,
+            requests: Mutex::new(rx),
+            connections,
+            closed,
+        })
+    }
+}
+
+// Limits of the connections
+struct Limits {
+    max_connections: usize,
+    idle_timeout: Duration,
+}
+
+// Accepts connections and starts a reader for each
+fn accept(
+    listener: TcpListener,
+    tx: mpsc::SyncSender<(Vec<u8>, Address)>,
+    limits: Limits,
+    connections: Connections,
+    closed: Arc<AtomicBool>,
+) {
+    while !closed.load(Ordering::SeqCst) {
+        let (stream, peer) = match listener.accept() {
+            Ok(connection) => connection,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL);
+                continue;
+            }
+            Err(e) => {
+                log::error!("Failed to accept connection: {:?}", e);
+                thread::sleep(ACCEPT_POLL);
+                continue;
+            }
+        };
+        let open = connections.lock().map(|c| c.len()).unwrap_or(0);
+        if open >= limits.max_connections {
+            log::warn!("Refuse connection from {}, {} connections are open", peer, open);
+            let _ = stream.shutdown(Shutdown::Both);
+            continue;
+        }
+        let writer = match stream
+            .set_nonblocking(false)
+            .and_then(|_| stream.set_nodelay(true))
+            .and_then(|_| stream.set_read_timeout(Some(IDLE_POLL.min(limits.idle_timeout))))
+            .and_then(|_| stream.set_write_timeout(Some(WRITE_TIMEOUT)))
+            .and_then(|_| stream.try_clone())
+        {
+            Ok(writer) => writer,
+            Err(e) => {
+                log::error!("Failed to set up connection from {}: {:?}", peer, e);
+                continue;
+            }
+        };
+        debug!("Accept connection from {}", peer);
+        let connection = Arc::new(Connection {
+            stream: Mutex::new(writer),
+            pending: Mutex::new((0, Instant::now())),
+        });
+        if let Ok(mut c) = connections.lock() {
+            c.insert(peer, connection.clone());
+        }
+        let tx = tx.clone();
+        let connections = connections.clone();
+        let idle_timeout = limits.idle_timeout;
+        let spawned = thread::Builder::new()
+            .name(format!("tcp-{}", peer))
+            .spawn(move || read(stream, peer, tx, connection, idle_timeout, connections));
+        if let Err(e) = spawned {
+            log::error!("Failed to start reader for {}: {:?}", peer, e);
+        }
+    }
+}
+
+// Queues the requests received on a connection until it is closed or idle
+//
+// The reader waits while the queue is full, the client's requests stay in the
+// socket buffers until the service catches up.
+fn read(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    tx: mpsc::SyncSender<(Vec<u8>, Address)>,
+    connection: Arc<Connection>,
+    idle_timeout: Duration,
+    connections: Connections,
+) {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        match take_prefixed(&mut buf) {
+            Ok(Some(msg)) => {
+                connection.begin();
+                if tx.send((msg, Address::Tcp(peer))).is_err() {
+                    break;
+                }
+                continue;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                debug!("Close connection from {}: {:?}", peer, e);
+                break;
+            }
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) => {
+                debug!("Connection closed by {}", peer);
+                break;
+            }
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => {
+                if connection.idle() >= idle_timeout {
+                    debug!("Close idle connection from {}", peer);
+                    break;
+                }
+            }
+            Err(e) => {
+                debug!("Close connection from {}: {:?}", peer, e);
+                break;
+            }
+        }
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+    if let Ok(mut c) = connections.lock() {
+        c.remove(&peer);
+    }
+}
+
+impl Transport for TcpTransport {
+    fn recv(&self, timeout: Duration) -> Result<Option<(Vec<u8>, Address)>> {
+        let requests = self.requests.lock().map_err(|_| Error::PoisonedMutex)?;
+        match requests.recv_timeout(timeout) {
+            Ok(request) => Ok(Some(request)),
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(Error::from(std::io::ErrorKind::NotConnected)),
+        }
+    }
+
+    fn send(&self, msg: &[u8], to: &Address) -> Result<()> {
+        let peer = match to {
+            Address::Tcp(peer) => peer,
+            _ => return Err(Error::from(std::io::ErrorKind::InvalidInput)),
+        };
+        // the lock of the connections is not held while writing
+        let connection = self
+            .connections
+            .lock()
+            .map_err(|_| Error::PoisonedMutex)?
+            .get(peer)
+            .cloned()
+            .ok_or_else(|| Error::from(std::io::ErrorKind::NotConnected))?;
+        connection.finish();
+        let mut stream = connection.stream.lock().map_err(|_| Error::PoisonedMutex)?;
+        stream.write_all(&encode_prefixed(msg))?;
+        Ok(())
+    }
+
+    fn drop_request(&self, from: &Address) {
+        if let Address::Tcp(peer) = from {
+            if let Some(connection) = self.connections.lock().ok().and_then(|c| c.get(peer).cloned()) {
+                connection.finish();
+            }
+        }
+    }
+
+    fn local_addr(&self) -> Result<Address> {
+        Ok(Address::Tcp(self.addr))
+    }
+}
+
+impl Drop for TcpTransport {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+        if let Ok(connections) = self.connections.lock() {
+            for connection in connections.values() {
+                if let Ok(s) = connection.stream.lock() {
+                    let _ = s.shutdown(Shutdown::Both);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::read_prefixed;
+
+    #[test]
+    fn idle_while_in_flight() {
+        let idle_timeout = Duration::from_millis(100);
+        let transport = TcpTransport::bind_with_limits("127.0.0.1:0".parse().unwrap(), 4, 1, idle_timeout).unwrap();
+        let addr = match transport.local_addr() {
+            Ok(Address::Tcp(addr)) => addr,
+            _ => panic!("not a TCP address"),
+        };
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        client.write_all(&encode_prefixed(b"request")).unwrap();
+        let (request, from) = transport.recv(Duration::from_secs(1)).unwrap().unwrap();
+        assert_eq!(request, b"request");
+        // a handler slower than the idle timeout
+        thread::sleep(idle_timeout * 3);
+        transport.send(b"reply", &from).unwrap();
+        assert_eq!(read_prefixed(&mut client).unwrap(), b"reply");
+        // the connection is closed once it was idle for the timeout
+        thread::sleep(idle_timeout * 3);
+        assert_eq!(client.read(&mut [0u8; 8]).unwrap_or(0), 0);
+        assert!(transport.send(b"late", &from).is_err());
+    }
+}
@@ -23,11 +23,17 @@
 use kubos_system::Config;
 use log::{debug,info};
 use std::collections::HashMap;
-use std::net::{SocketAddr};
+use std::net::{SocketAddr,UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 use std::str::FromStr;
 use std::io::Write;
 use crate::error::*;
+use crate::codec::Codec;
+use crate::interface::verify_interface;
+
+// Time to wait for the interface hash of the service
+const INTERFACE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
 
 /// Type definition for a CLI tool
 pub type InputFn = dyn Fn() -> Result<String> + std::marker::Send + std::marker::Sync + 'static;
@@ -50,16 +56,41 @@ pub enum Mode {
 pub struct UdpPassthrough {
     pub socket: SocketAddr,
     pub to: SocketAddr,
+    pub codec: Codec,
+    /// Send commands even if the interface of the service differs from the terminal
+    pub force: bool,
+    // set once the interface of the service matched
+    verified: Arc<AtomicBool>,
 }
 impl UdpPassthrough {
-    pub fn new(bind: String, target: String) -> Self {
-        let socket = SocketAddr::from_str(bind.as_str()).unwrap();
-        let to = SocketAddr::from_str(target.as_str()).unwrap();
+    /// Returns `ErrorKind::InvalidInput` if `bind` or `target` is not a socket address
+    pub fn new(bind: String, target: String) -> Result<Self> {
+        let socket = SocketAddr::from_str(bind.as_str())
+            .map_err(|_| Error::from(std::io::ErrorKind::InvalidInput))?;
+        let to = SocketAddr::from_str(target.as_str())
+            .map_err(|_| Error::from(std::io::ErrorKind::InvalidInput))?;
 
-        Self {
+        Ok(Self {
             socket,
             to,
+            codec: Codec::default(),
+            force: false,
+            verified: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Compares the interface of the service with `local`, usually the generated `INTERFACE_HASH`
+    ///
+    /// The generated `output` calls this before the first command of the terminal.
+    /// Returns `Error::InterfaceMismatch` if the interfaces differ, unless `force` is set.
+    pub fn check_interface(&self, local: u64) -> Result<()> {
+        if self.force || self.verified.load(Ordering::SeqCst) {
+            return Ok(());
         }
+        let socket = UdpSocket::bind(self.socket)?;
+        verify_interface(&socket, &self.to, &self.codec, local, Some(INTERFACE_TIMEOUT))?;
+        self.verified.store(true, Ordering::SeqCst);
+        Ok(())
     }
 }
 
@@ -146,7 +177,7 @@ impl Context {
 ///     schema::MutationRoot,
 ///     socket,
 ///     target,
-/// ).start();
+/// )?.start();
 /// ```
 pub struct Service {
     config: Config,
@@ -166,6 +197,9 @@ impl Service {
     /// `mutation` - The root mutation struct holding all other GraphQL mutations.
     /// `socket` - UDP Socket to bind on the ground computer to enable UDP msgs
     /// `target` - Address of service running on the satellite
+    ///
+    /// Returns an error if `socket` or `target` is not a socket address,
+    /// or the codec settings in `config` are invalid.
     pub fn new(
         config: Config,
         socket: String,
@@ -173,11 +207,13 @@ impl Service {
         command: Vec<String>,
         input: Arc<InputFn>,
         output: Arc<OutputFn>,        
-    ) -> Self
+    ) -> Result<Self>
     {
+        let mut udp_pass = UdpPassthrough::new(socket,target)?;
+        udp_pass.codec = Codec::from_config(&config)?;
         let context = Context {
             storage: Arc::new(RwLock::new(HashMap::new())),
-            udp_pass: UdpPassthrough::new(socket,target),
+            udp_pass,
         };
         let functions = Functions {
             output,
@@ -193,7 +229,16 @@ impl Service {
             (Mode::Run, Some(command[3..].to_vec().concat()))
         };
 
-        Service { config, context, command, functions, mode }
+        Ok(Service { config, context, command, functions, mode })
+    }
+
+    /// Sends commands even if the interface of the service differs from the terminal build
+    ///
+    /// By default the terminal refuses to send commands other than the built-in commands
+    /// to a service with a different interface, as their replies would not decode.
+    pub fn force_interface(mut self) -> Self {
+        self.context.udp_pass.force = true;
+        self
     }
 
     /// Starts the service's GraphQL/UDP server. This function runs
@@ -17,15 +17,30 @@
 // 
 
 #[macro_export]
-macro_rules! service_macro {
+macro_rules! terminal_service_macro {
+    // subsystem given as a type, used by #[cubeos_service]
+    // the terminal does not use the subsystem
+    (
+        use $error: ty;
+        impl $sub: ty {
+            $($entries: tt)*
+        }
+    ) => {
+        $crate::terminal_service_macro!{
+            use $error;
+            crate::Subsystem {
+                $($entries)*
+            }
+        }
+    };
     (
         use $error: ty;
         $krate: tt ::$strukt: tt {
             $(            
-                query: $type_q: ident => fn $func_q: tt (&$(mut )?self $(, $msg_q: tt:$cmd_q: ty)*) -> $ign1_q: tt<$rep_q: ty> $(; out: $gql_q: ty)?;
+                query: $type_q: ident $(= $id_q: literal)? => fn $func_q: tt (&$(mut )?self $(, $msg_q: tt:$cmd_q: ty)*) -> $ign1_q: tt<$rep_q: ty> $(; out: $gql_q: ty)? $(; timeout: $_timeout_q: literal)?;
             )*
             $(
-                mutation: $type_m: ident => fn $func_m: tt (&$(mut )?self $(, $msg_m: tt:$cmd_m: ty)*) -> $ign1_m: tt<$rep_m: ty>;
+                mutation: $type_m: ident $(= $id_m: literal)? => fn $func_m: tt (&$(mut )?self $(, $msg_m: tt:$cmd_m: ty)*) -> $ign1_m: tt<$rep_m: ty> $(; timeout: $_timeout_m: literal)?;
             )*
         }
     ) => {    
@@ -36,7 +51,7 @@ macro_rules! service_macro {
         use cubeos_service::serde_json::to_string_pretty;
         use cubeos_service::udp_rs::Message;
         use cubeos_service::bincode;
-        use cubeos_service::command_id;
+        use cubeos_service::{command_id,next_seq,transfer,Frame};
         use cubeos_service::dialoguer::{MultiSelect,Select};
         use terminal_macro::terminal_macro;
         use strum::IntoEnumIterator;
@@ -44,13 +59,16 @@ macro_rules! service_macro {
         use std::io::Write;
 
         command_id!{
-            // Ping,
-            // LastCmd,
-            // LastErr,
-            $($type_q,)*
-            $($type_m,)*
+            $($type_q $(= $id_q)?,)*
+            $($type_m $(= $id_m)?,)*
         }
 
+        // hash of the command table, compared with the service before the first command
+        pub const INTERFACE_HASH: u64 = cubeos_service::interface_hash(&[
+            $(cubeos_service::command_hash(stringify!($type_q), CommandID::$type_q.id(), &[$(stringify!($cmd_q)),*], stringify!($rep_q)),)*
+            $(cubeos_service::command_hash(stringify!($type_m), CommandID::$type_m.id(), &[$(stringify!($cmd_m)),*], stringify!($rep_m)),)*
+        ]);
+
         terminal_macro!(
             $($type_q$(, $msg_q, $cmd_q),*;)*
             $($type_m$(, $msg_m, $cmd_m),*;)*
@@ -58,7 +76,7 @@ macro_rules! service_macro {
         
         // function to connect to and send UDP messages to the satellite
         // binds socket and sends to target addresses specified in the config.toml file
-        pub fn udp_passthrough(cmd: Vec<u8>, udp: &UdpPassthrough) -> CubeOSResult<Vec<u8>> {
+        pub fn udp_passthrough(cmd: Vec<u8>, udp: &cubeos_service::terminal::UdpPassthrough) -> CubeOSResult<Vec<u8>> {
             // let mut buf = [0u8; 255];
             let socket = match UdpSocket::bind(udp.socket) {
                 Ok(s) => s,
@@ -67,16 +85,11 @@ macro_rules! service_macro {
             // let socket = s.unwrap();
             debug!("{:?}",udp.to);
             debug!("Cmd: {:?}", cmd);
-            match socket.send_msg(&cmd,&udp.to) {
-                Ok(_) => {
-                    debug!("Sending");
-                    match socket.recv_msg() {
-                        Ok((b,a)) => {                            
-                            debug!("Received: {:?}", b);
-                            Ok(b)
-                        },
-                        Err(_) => Err(CubeOSError::NoCmd),
-                    }
+            // replies to earlier commands are dropped by transfer
+            match transfer(&socket,&udp.to,&udp.codec,cmd,None) {
+                Ok(b) => {                            
+                    debug!("Received: {:?}", b);
+                    Ok(b)
                 },
                 Err(_) => Err(CubeOSError::NoCmd),
             }
@@ -89,33 +102,49 @@ macro_rules! service_macro {
             }
         }
 
-        fn handle_id(mut cmd: Vec<u8>) -> Vec<u8> {
-            let mut first = cmd.remove(0);
+        fn handle_id(mut cmd: Vec<u8>) -> CubeOSResult<Vec<u8>> {
+            if cmd.is_empty() {
+                return Err(CubeOSError::Truncated);
+            }
+            // variant index of the Command enum
+            let first = cmd.remove(0);
 
-            let first_u16 = u16::from(first) + 1;
-            let mut buf = Vec::new();
-            buf.append(&mut first_u16.to_be_bytes().to_vec());
-            buf.append(&mut cmd);
-            buf
+            let id = CommandID::ALL.get(first as usize).ok_or(CubeOSError::NoCmd)?;
+            Frame::encode(u16::try_from(*id)?, next_seq(), &cmd)
         }
         
-        pub fn output(mut command: String, udp: UdpPassthrough) -> String {
+        pub fn output(mut command: String, udp: cubeos_service::terminal::UdpPassthrough) -> String {
             let cmd_enum = serde_json::from_str::<Command>(&command).unwrap();
 
             let cmd_ser = match bincode::serialize(&cmd_enum) {
                 Ok(c) => c,
                 Err(e) => return handle_error(CubeOSError::from(e)),
             };
-            let cmd_fin = handle_id(cmd_ser);
+            let cmd_fin = match handle_id(cmd_ser) {
+                Ok(c) => c,
+                Err(e) => return handle_error(e),
+            };
+            // the built-in commands work with any interface, e.g. to ask for the interface of the service
+            let builtin = Frame::parse(&cmd_fin).map(|f| f.id < cubeos_service::FIRST_USER_ID).unwrap_or(false);
+            if !builtin {
+                match udp.check_interface(INTERFACE_HASH) {
+                    Ok(()) => {},
+                    Err(CubeOSError::InterfaceMismatch) => return "The interface of the service differs from this terminal, rebuild the terminal or force the interface".to_string(),
+                    Err(e) => return handle_error(e),
+                }
+            }
             match udp_passthrough(cmd_fin,&udp) {
                 Ok(buf) => {
-                    match u16::from_be_bytes([buf[0],buf[1]]) {
-                        0 => return handle_error(bincode::deserialize::<CubeOSError>(&buf[2..]).unwrap()).to_string(),
-                        65535 => return handle_error(bincode::deserialize::<CubeOSError>(&buf[2..]).unwrap()).to_string(),
-                        _ => {
+                    let frame = match Frame::parse(&buf) {
+                        Ok(f) => f,
+                        Err(e) => return handle_error(e),
+                    };
+                    match frame.is_error() {
+                        true => return handle_error(frame.error()),
+                        false => {
                             match cmd_enum {
                                 $(Command::$type_q(_) => {
-                                    match bincode::deserialize::<$rep_q>(&buf[2..]) {
+                                    match frame.data::<$rep_q>() {
                                         Ok(c) => match serde_json::to_string_pretty(&<$($gql_q)?>::from(c)) {
                                             Ok(s) => s,
                                             Err(e) => e.to_string(),
@@ -124,6 +153,34 @@ macro_rules! service_macro {
                                     }                                    
                                 },)*
                                 $(Command::$type_m(_) => "Success".to_string(),)*
+                                Command::Ping(_) => "Pong".to_string(),
+                                Command::LastCmd(_) => match frame.data::<Vec<u8>>() {
+                                    Ok(c) => format!("{:?}", c),
+                                    Err(e) => e.to_string(),
+                                },
+                                Command::LastErr(_) => match frame.data::<CubeOSError>() {
+                                    Ok(c) => handle_error(c),
+                                    Err(e) => e.to_string(),
+                                },
+                                Command::Describe(_) => match frame.data::<Vec<cubeos_service::CommandInfo>>() {
+                                    Ok(c) => match serde_json::to_string_pretty(&c) {
+                                        Ok(s) => s,
+                                        Err(e) => e.to_string(),
+                                    },
+                                    Err(e) => e.to_string(),
+                                },
+                                Command::Interface(_) => match frame.data::<u64>() {
+                                    Ok(c) if c == INTERFACE_HASH => format!("{:#018x}", c),
+                                    Ok(c) => format!("{:#018x} (terminal: {:#018x})", c, INTERFACE_HASH),
+                                    Err(e) => e.to_string(),
+                                },
+                                Command::LastHistory(_) => match frame.data::<cubeos_service::History>() {
+                                    Ok(c) => match serde_json::to_string_pretty(&c) {
+                                        Ok(s) => s,
+                                        Err(e) => e.to_string(),
+                                    },
+                                    Err(e) => e.to_string(),
+                                },
                                 _ => format!("Invalid command: {}", command),                            
                             }
                         }
@@ -137,7 +194,7 @@ macro_rules! service_macro {
         }
 
         fn handle_input(selection: usize) -> Result<String> {
-            match CommandID::try_from(selection as u16) {
+            match CommandID::ALL.get(selection).copied().ok_or(CubeOSError::NoCmd) {
                 Ok(id) => match id {
                     $(CommandID::$type_q => {
                         println!("{}",stringify!($type_q));
@@ -151,6 +208,12 @@ macro_rules! service_macro {
                         let cmd = Command::$type_m(input);
                         Ok(serde_json::to_string_pretty(&cmd).unwrap())
                     },)*
+                    CommandID::Ping => Ok(serde_json::to_string_pretty(&Command::Ping(Ping {})).unwrap()),
+                    CommandID::LastCmd => Ok(serde_json::to_string_pretty(&Command::LastCmd(LastCmd {})).unwrap()),
+                    CommandID::LastErr => Ok(serde_json::to_string_pretty(&Command::LastErr(LastErr {})).unwrap()),
+                    CommandID::LastHistory => Ok(serde_json::to_string_pretty(&Command::LastHistory(LastHistory {})).unwrap()),
+                    CommandID::Describe => Ok(serde_json::to_string_pretty(&Command::Describe(Describe {})).unwrap()),
+                    CommandID::Interface => Ok(serde_json::to_string_pretty(&Command::Interface(Interface {})).unwrap()),
                 },
                 Err(e) => Err(e),
             }
@@ -161,10 +224,16 @@ macro_rules! service_macro {
             match Select::new()
                 $(.item(stringify!($type_q)))*
                 $(.item(stringify!($type_m)))*
+                .item("Ping")
+                .item("LastCmd")
+                .item("LastErr")
+                .item("LastHistory")
+                .item("Describe")
+                .item("Interface")
                 .interact() 
             {
                 Ok(selection) => {
-                    Ok(handle_input(selection+1)?)
+                    Ok(handle_input(selection)?)
                 },
                 Err(e) => Err(e.into()),
             }         
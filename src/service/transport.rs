@@ -0,0 +1,309 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Contributed by: Patrick Oppel (patrick.oppel94@gmail.com)
+//
+
+// Transports of the service
+//
+// A transport receives the encoded requests and sends the replies.
+// The service only sees complete messages and the address of their sender,
+// so the same `udp_handler` is served on every transport.
+// The transport is chosen from the service's config section:
+//
+// ```toml,ignore
+// [service-name.addr]    # UDP, the default
+// ip = "0.0.0.0"
+// port = 8020
+// protocol = "tcp"       # TCP with length-prefixed messages instead of UDP
+// max_connections = 16  # TCP only, connections kept open, default 16
+// idle_timeout = 60000  # TCP only, milliseconds until an idle connection is closed, default 60000
+//
+// [service-name.addr]    # Unix datagram socket for clients on the same computer
+// path = "/run/cubeos/service-name.sock"
+// mode = 0o660           # permissions of the socket file, default from umask
+//
+// [service-name.uart]    # serial link, used instead of addr if present
+// path = "/dev/ttyS1"
+// baud = 115200          # default 115200
+// framing = "slip"       # slip or kiss, default slip, kiss with a csp section
+//
+// [service-name.csp]     # CSP on the uart link, see `CspNode`
+// address = 5
+// port = 10
+// ```
+//
+// Access to a Unix socket is controlled by the permissions of its file,
+// a client needs write permission to send requests. Clients bind a socket file
+// of their own to receive the replies.
+
+use super::csp::{CspInterface, CspNode, CSP_MAX_BIND_PORT};
+use super::serial::SerialTransport;
+use super::tcp::{TcpTransport, DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_CONNECTIONS};
+use super::udp::pool_from_config;
+use crate::address::Address;
+use crate::csp::{CspPriority, CSP_MAX_ADDRESS};
+use crate::error::*;
+use crate::framing::Framing;
+use kubos_system::Config;
+use std::fs;
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_BAUD: usize = 115200;
+
+/// Link on which a service receives requests and sends replies
+///
+/// `recv` is called by one thread of the service, `send` by all workers.
+pub trait Transport: Send + Sync {
+    /// Waits up to `timeout` for the next message and returns it with its sender
+    ///
+    /// Returns `None` if no message arrived in time.
+    fn recv(&self, timeout: Duration) -> Result<Option<(Vec<u8>, Address)>>;
+
+    /// Sends a message to `to`
+    fn send(&self, msg: &[u8], to: &Address) -> Result<()>;
+
+    /// Local address of the transport
+    fn local_addr(&self) -> Result<Address>;
+
+    /// Called for a request from `from` that the service drops without a reply,
+    /// e.g. a retransmission of a request that is still being handled
+    fn drop_request(&self, _from: &Address) {}
+}
+
+/// UDP socket
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    pub fn bind(addr: SocketAddr) -> Result<Self> {
+        Ok(UdpTransport { socket: UdpSocket::bind(addr)? })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn recv(&self, timeout: Duration) -> Result<Option<(Vec<u8>, Address)>> {
+        self.socket.set_read_timeout(Some(timeout))?;
+        let mut buf = vec![0u8; u16::MAX as usize];
+        match self.socket.recv_from(&mut buf) {
+            Ok((n, a)) => {
+                buf.truncate(n);
+                Ok(Some((buf, Address::Ip(a))))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn send(&self, msg: &[u8], to: &Address) -> Result<()> {
+        match to {
+            Address::Ip(to) => {
+                self.socket.send_to(msg, to)?;
+                Ok(())
+            }
+            _ => Err(Error::from(std::io::ErrorKind::InvalidInput)),
+        }
+    }
+
+    fn local_addr(&self) -> Result<Address> {
+        Ok(Address::Ip(self.socket.local_addr()?))
+    }
+}
+
+/// Unix datagram socket
+///
+/// The socket file is removed when the transport is dropped.
+pub struct UnixTransport {
+    socket: UnixDatagram,
+    path: PathBuf,
+}
+
+impl UnixTransport {
+    /// Binds the socket file at `path` and sets its permissions to `mode`
+    ///
+    /// A socket file left behind by a previous run is replaced, if a service
+    /// still receives on it `ErrorKind::AddrInUse` is returned.
+    /// Missing parent directories are created.
+    /// With a `mode` the file is bound in a private directory and moved to `path`
+    /// once it has its permissions, so it is never reachable with the umask's.
+    pub fn bind(path: &Path, mode: Option<u32>) -> Result<Self> {
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            if !meta.file_type().is_socket() {
+                log::error!("{} exists and is not a socket", path.display());
+                return Err(Error::from(std::io::ErrorKind::AlreadyExists));
+            }
+            if UnixDatagram::unbound()?.connect(path).is_ok() {
+                log::error!("{} is in use by another service", path.display());
+                return Err(Error::from(std::io::ErrorKind::AddrInUse));
+            }
+            fs::remove_file(path)?;
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let socket = match mode {
+            Some(mode) => bind_private(path, mode)?,
+            None => UnixDatagram::bind(path)?,
+        };
+        Ok(UnixTransport { socket, path: path.to_path_buf() })
+    }
+}
+
+// Binds `<path>.<pid>/sock` in a directory only this user can access,
+// sets its permissions to `mode` and renames it to `path`
+fn bind_private(path: &Path, mode: u32) -> Result<UnixDatagram> {
+    let dir = PathBuf::from(format!("{}.{}", path.display(), std::process::id()));
+    // left behind by a process with the same pid
+    let _ = fs::remove_dir_all(&dir);
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let tmp = dir.join("sock");
+    let result = UnixDatagram::bind(&tmp).and_then(|socket| {
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(mode))?;
+        fs::rename(&tmp, path)?;
+        Ok(socket)
+    });
+    let _ = fs::remove_dir_all(&dir);
+    Ok(result?)
+}
+
+impl Transport for UnixTransport {
+    fn recv(&self, timeout: Duration) -> Result<Option<(Vec<u8>, Address)>> {
+        self.socket.set_read_timeout(Some(timeout))?;
+        let mut buf = vec![0u8; u16::MAX as usize];
+        match self.socket.recv_from(&mut buf) {
+            Ok((n, a)) => match a.as_pathname() {
+                Some(path) => {
+                    buf.truncate(n);
+                    Ok(Some((buf, Address::Unix(path.to_path_buf()))))
+                }
+                // the reply could not be sent
+                None => {
+                    log::warn!("Drop request from unbound socket");
+                    Ok(None)
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn send(&self, msg: &[u8], to: &Address) -> Result<()> {
+        match to {
+            Address::Unix(path) => {
+                self.socket.send_to(msg, path)?;
+                Ok(())
+            }
+            _ => Err(Error::from(std::io::ErrorKind::InvalidInput)),
+        }
+    }
+
+    fn local_addr(&self) -> Result<Address> {
+        Ok(Address::Unix(self.path.clone()))
+    }
+}
+
+impl Drop for UnixTransport {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Opens the transport selected in the service's config section
+pub fn transport_from_config(config: &Config) -> Result<Box<dyn Transport>> {
+    let csp = config.get("csp").is_some();
+    if let Some(uart) = config.get("uart") {
+        let path = uart
+            .get("path")
+            .and_then(|p| p.as_str())
+            .ok_or_else(|| Error::InvalidConfig("uart.path".to_string()))?;
+        let baud = match uart.get("baud").map(|b| b.as_integer()) {
+            Some(Some(b)) if b > 0 => b as usize,
+            Some(_) => return Err(Error::InvalidConfig("uart.baud".to_string())),
+            None => DEFAULT_BAUD,
+        };
+        let framing = match uart.get("framing").map(|f| f.as_str().and_then(Framing::from_name)) {
+            Some(Some(f)) => f,
+            Some(None) => return Err(Error::InvalidConfig("uart.framing".to_string())),
+            None if csp => Framing::Kiss,
+            None => Framing::Slip,
+        };
+        let serial = SerialTransport::open(path, baud, framing)?;
+        return match csp {
+            true => csp_from_config(config, Arc::new(serial)),
+            false => Ok(Box::new(serial)),
+        };
+    }
+    if csp {
+        return Err(Error::InvalidConfig("uart".to_string()));
+    }
+    match Address::from_config(config)? {
+        Address::Unix(path) => {
+            let mode = match config.get("addr").and_then(|a| a.get("mode").map(|m| m.as_integer())) {
+                Some(Some(m)) if (0..=0o7777).contains(&m) => Some(m as u32),
+                Some(_) => return Err(Error::InvalidConfig("addr.mode".to_string())),
+                None => None,
+            };
+            Ok(Box::new(UnixTransport::bind(&path, mode)?))
+        }
+        Address::Ip(addr) => Ok(Box::new(UdpTransport::bind(addr)?)),
+        Address::Tcp(addr) => {
+            let (_, queue_depth) = pool_from_config(config)?;
+            let addr_key = |key: &str| config.get("addr").and_then(|a| a.get(key).map(|v| v.as_integer()));
+            let max_connections = match addr_key("max_connections") {
+                Some(Some(m)) if m > 0 => m as usize,
+                Some(_) => return Err(Error::InvalidConfig("addr.max_connections".to_string())),
+                None => DEFAULT_MAX_CONNECTIONS,
+            };
+            let idle_timeout = match addr_key("idle_timeout") {
+                Some(Some(t)) if t > 0 => Duration::from_millis(t as u64),
+                Some(_) => return Err(Error::InvalidConfig("addr.idle_timeout".to_string())),
+                None => DEFAULT_IDLE_TIMEOUT,
+            };
+            Ok(Box::new(TcpTransport::bind_with_limits(addr, queue_depth, max_connections, idle_timeout)?))
+        }
+        _ => Err(Error::InvalidConfig("addr".to_string())),
+    }
+}
+
+// Binds the port of the service on a CSP node on `iface`
+fn csp_from_config(config: &Config, iface: Arc<dyn CspInterface>) -> Result<Box<dyn Transport>> {
+    let csp = |key: &str| config.get("csp").and_then(|c| c.get(key).cloned());
+    let address = match csp("address").map(|a| a.as_integer()) {
+        Some(Some(a)) if (0..=CSP_MAX_ADDRESS as i64).contains(&a) => a as u8,
+        _ => return Err(Error::InvalidConfig("csp.address".to_string())),
+    };
+    let port = match csp("port").map(|p| p.as_integer()) {
+        Some(Some(p)) if (0..=CSP_MAX_BIND_PORT as i64).contains(&p) => p as u8,
+        _ => return Err(Error::InvalidConfig("csp.port".to_string())),
+    };
+    let priority = match csp("priority").map(|p| p.as_str().and_then(CspPriority::from_name)) {
+        Some(Some(p)) => p,
+        Some(None) => return Err(Error::InvalidConfig("csp.priority".to_string())),
+        None => CspPriority::Normal,
+    };
+    let crc = match csp("crc").map(|c| c.as_bool()) {
+        Some(Some(c)) => c,
+        Some(None) => return Err(Error::InvalidConfig("csp.crc".to_string())),
+        None => true,
+    };
+    let node = Arc::new(CspNode::new(iface, address)?.priority(priority).crc(crc));
+    Ok(Box::new(node.bind(port)?))
+}
@@ -23,14 +23,36 @@
 use kubos_system::Config;
 use log::info;
 use std::collections::HashMap;
-use std::net::{SocketAddr,UdpSocket};
-use std::sync::{Arc, RwLock};
+use std::net::SocketAddr;
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use crate::address::Address;
 use crate::error::*;
-use udp_rs::Message;
+use crate::frame::{Frame,ERROR_ID,FATAL_ID};
+use crate::codec::Codec;
+use crate::builtin::LAST_HISTORY_LEN;
+use crate::last::{set_scope,LastTracker,Scope};
+use super::dedup::{Lookup,ReplyCache};
+use super::watchdog::{Job,Watchdog,WATCHDOG_INTERVAL};
+use super::transport::{transport_from_config,Transport};
 use log::debug;
 
+const DEFAULT_WORKERS: usize = 1;
+pub(super) const DEFAULT_QUEUE_DEPTH: usize = 8;
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+// Interval in which the listener checks for shutdown
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
 /// Type definition for a "UDP" server pointer
-pub type UdpFn<T, Vec> = dyn Fn(&mut T, &mut Vec) -> Result<Vec<>> + std::marker::Send + std::marker::Sync + 'static;
+///
+/// The handler locks the subsystem itself, for reading or writing depending on the command.
+pub type UdpFn<T, Vec> = dyn Fn(&RwLock<T>, &mut Vec) -> Result<Vec<>> + std::marker::Send + std::marker::Sync + 'static;
+
+/// Type definition for the function called on shutdown
+pub type ShutdownFn<T> = dyn Fn(&mut T) -> Result<()> + std::marker::Send + std::marker::Sync + 'static;
 
 /// Context struct used by a service to provide,
 /// subsystem access and persistent storage.
@@ -40,12 +62,14 @@ pub struct Context<T: Clone + std::marker::Send> {
     pub subsystem: Arc<RwLock<T>>,
     ///
     pub storage: Arc<RwLock<HashMap<String, String>>>,
+    /// Recent commands and errors, if the subsystem does not implement `Last`
+    pub last: Arc<LastTracker>,
 }
 
 /// This structure represents a hardware service.
 ///
 /// Specifically the functionality provided by this struct
-/// exists to provide a UDP interface.
+/// exists to provide a UDP interface, or another `Transport`.
 ///
 /// ### Examples
 ///
@@ -67,6 +91,10 @@ pub struct Service<T:Clone + std::marker::Send + 'static>{
     // control: ServiceControlBlock, 
     /// Function pointer to a function that defines how to handle UDP requests
     udp_handler: Option<Arc<UdpFn<T, Vec<u8>>>>,  
+    /// Function called with the subsystem on shutdown
+    on_shutdown: Option<Arc<ShutdownFn<T>>>,
+    /// Transport used instead of the one in the config
+    transport: Option<Arc<dyn Transport>>,
 }
 
 impl <T: Clone + std::marker::Send + std::marker::Sync + 'static> Service<T> {
@@ -87,91 +115,417 @@ impl <T: Clone + std::marker::Send + std::marker::Sync + 'static> Service<T> {
         let context = Context {
             subsystem: Arc::new(RwLock::new(subsystem)),
             storage: Arc::new(RwLock::new(HashMap::new())),
+            last: Arc::new(LastTracker::new(LAST_HISTORY_LEN)),
         }; 
         
-        Service { config, context, udp_handler }
+        Service { config, context, udp_handler, on_shutdown: None, transport: None }
+    }
+
+    /// Sets a function that is called with the subsystem when the service shuts down
+    pub fn on_shutdown(mut self, hook: Arc<ShutdownFn<T>>) -> Self {
+        self.on_shutdown = Some(hook);
+        self
+    }
+
+    /// Serves the requests on `transport` instead of the transport in the config
+    ///
+    /// ```rust,ignore
+    /// let link = SerialTransport::open("/dev/ttyS1", 115200, Framing::Kiss)?;
+    /// Service::new(config, sub, Some(Arc::new(udp_handler)))
+    ///     .transport(Arc::new(link))
+    ///     .start();
+    /// ```
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        self
     }
 
     /// Starts the service's UDP server. This function runs
-    /// without return.
+    /// until the process receives SIGTERM or SIGINT.
+    ///
+    /// Requests are handled by a pool of worker threads. Queries of different workers
+    /// run concurrently, mutations have exclusive access to the subsystem.
+    /// If the request queue is full, the request is rejected with `Error::Busy`.
+    /// If a command exceeds its timeout, the client receives `Error::TimedOut`
+    /// and the worker is reported as stuck until the command returns.
+    ///
+    /// ```toml,ignore
+    /// [service-name]
+    /// workers = 4       # number of worker threads, default 1
+    /// queue_depth = 8   # number of requests waiting for a worker, default 8
+    /// command_timeout = 2000  # milliseconds, no timeout if not set
+    /// shutdown_timeout = 5000 # milliseconds to drain the workers on shutdown, default 5000
+    /// ```
     ///
     /// # Panics
     ///
     /// The UDP interface will panic if the ip address and port provided
-    /// cannot be bound (like if they are already in use), or if for some reason the socket fails
-    /// to receive a message.
+    /// cannot be bound (like if they are already in use), or if the serial device cannot be opened.
     pub fn start(self) {
-        let hosturl = self
-            .config
-            .hosturl()
-            .ok_or_else(|| {
-                log::error!("Failed to load service URL");
-                "Failed to load service URL"
-            })
-            .unwrap();
-        let addr = hosturl
-            .parse::<SocketAddr>()
+        let shutdown = Arc::new(AtomicBool::new(false));
+        for signal in &[SIGTERM, SIGINT] {
+            signal_hook::flag::register(*signal, shutdown.clone())
+                .expect("couldn't register signal handler");
+        }
+        let server = self.load().unwrap();
+        self.run(server, shutdown);
+    }
+
+    /// Starts the service's UDP server on a new thread
+    ///
+    /// Unlike `start`, no signal handlers are installed.
+    /// The service runs until `ShutdownHandle::shutdown` is called.
+    pub fn spawn(self) -> Result<ShutdownHandle> {
+        let server = self.load()?;
+        let addr = server.transport.local_addr()?;
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let flag = shutdown.clone();
+        let thread = thread::Builder::new()
+            .name("service".to_string())
+            .spawn(move || self.run(server, flag))?;
+        Ok(ShutdownHandle { addr, shutdown, thread })
+    }
+
+    // Reads the config and opens the transport
+    fn load(&self) -> Result<Server> {
+        let codec = Codec::from_config(&self.config)
             .map_err(|err| {
-                log::error!("Failed to parse SocketAddr: {:?}", err);
+                log::error!("Failed to load codec: {:?}", err);
                 err
-            })
-            .unwrap();
-        info!("Listening on: {}", addr);
-
-        let udp_handler = self.udp_handler.unwrap();
+            })?;
+        let cache = ReplyCache::from_config(&self.config)
+            .map_err(|err| {
+                log::error!("Failed to load duplicate window: {:?}", err);
+                err
+            })?;
+        let (workers, queue_depth) = pool_from_config(&self.config)
+            .map_err(|err| {
+                log::error!("Failed to load worker pool: {:?}", err);
+                err
+            })?;
+        let watchdog = Watchdog::from_config(&self.config, workers)
+            .map_err(|err| {
+                log::error!("Failed to load command timeout: {:?}", err);
+                err
+            })?;
+        let shutdown_timeout = shutdown_timeout_from_config(&self.config)
+            .map_err(|err| {
+                log::error!("Failed to load shutdown timeout: {:?}", err);
+                err
+            })?;
+        let transport: Arc<dyn Transport> = match &self.transport {
+            Some(t) => t.clone(),
+            None => Arc::from(transport_from_config(&self.config)
+                .map_err(|err| {
+                    log::error!("Failed to open transport: {:?}", err);
+                    err
+                })?),
+        };
+        info!("Listening on: {}", transport.local_addr()?);
+        Ok(Server {
+            transport,
+            codec,
+            cache: Arc::new(Mutex::new(cache)),
+            workers,
+            queue_depth,
+            watchdog: Arc::new(watchdog),
+            shutdown_timeout,
+        })
+    }
 
-        let socket = UdpSocket::bind(addr).expect("couldn't bind to address");
+    fn run(self, server: Server, shutdown: Arc<AtomicBool>) {
+        let Server { transport, codec, cache, workers, queue_depth, watchdog, shutdown_timeout } = server;
+        let udp_handler = self.udp_handler.clone().unwrap();
 
-        // loop for UDP handling
-        // listens for UDP messages on socket
+        // worker pool
+        // each worker takes decoded requests from the queue,
         // uses udp_handler function supplied by service to handle the cmd
-        // returns answer to sender
-        debug!("Start listener on: {:?}", socket);
-        loop{
-            match socket.recv_msg() {
-                Ok((mut b,a)) => {
-                    let sock = UdpSocket::bind("0.0.0.0:0").expect("couldn't bind to address");
-                    debug!("{:?}", sock);
-                    // let handler = udp_handler.lock().unwrap().clone();
-                    let handler = udp_handler.clone();
-                    let s = self.context.subsystem.clone();
-                    // thread::spawn(move || {
-                        match handler(&mut s.try_write().unwrap(),&mut b) {
-                            Ok(x) => {                                
-                                debug!("Send: {:?} to {:?}",&x,&a);
-                                sock.send_msg(&x,&a).expect("Couldn't send")
+        // and returns answer to sender
+        let (tx, rx) = mpsc::sync_channel::<(Vec<u8>, Vec<u8>, Address)>(queue_depth);
+        let rx = Arc::new(Mutex::new(rx));
+        let queued = Arc::new(AtomicUsize::new(0));
+        // every worker reports here when it has stopped
+        let (done_tx, done_rx) = mpsc::channel::<usize>();
+        for i in 0..workers {
+            let rx = rx.clone();
+            let queued = queued.clone();
+            let handler = udp_handler.clone();
+            let s = self.context.subsystem.clone();
+            let last = self.context.last.clone();
+            let codec = codec.clone();
+            let cache = cache.clone();
+            let slot = watchdog.slots[i].clone();
+            let timeout = watchdog.timeout;
+            let done = done_tx.clone();
+            let transport = transport.clone();
+            thread::Builder::new()
+                .name(format!("worker-{}", i))
+                .spawn(move || {
+                    debug!("Start worker-{}", i);
+                    loop {
+                        let job = match rx.lock() {
+                            Ok(rx) => rx.recv(),
+                            Err(_) => break,
+                        };
+                        let (b, mut f, a) = match job {
+                            Ok(job) => job,
+                            // the receiver has stopped and the queue is drained
+                            Err(_) => break,
+                        };
+                        queued.fetch_sub(1, Ordering::SeqCst);
+                        let seq = Frame::peek_seq(&f).unwrap_or(0);
+                        let start = Instant::now();
+                        slot.begin(Job {
+                            sender: a.clone(),
+                            request: b.clone(),
+                            seq,
+                            start,
+                            deadline: timeout.map(|t| start + t),
+                        });
+                        set_scope(Some(Scope { sender: a.socket_addr(), last: last.clone() }));
+                        let reply = handler(&s, &mut f)
+                            .and_then(|x| codec.encode_reply(x))
+                            .or_else(|e| codec.encode_reply(handle_err(&e, seq)));
+                        set_scope(None);
+                        if !slot.finish() {
+                            log::warn!("worker-{} recovered after {:?}, drop late reply to {:?}", i, start.elapsed(), &a);
+                            continue;
+                        }
+                        match reply {
+                            Ok(x) => {
+                                debug!("Send: {:?} to {:?}", &x, &a);
+                                send(&*transport, &x, &a);
+                                if let Ok(mut cache) = cache.lock() {
+                                    cache.insert(&a, &b, x);
+                                }
                             }
                             Err(e) => {
-                                sock.send_to(&handle_err(&e),&a).expect("couldn't send");
+                                log::error!("Failed to encode reply: {:?}", e);
+                            }
+                        }
+                    }
+                    let _ = done.send(i);
+                })
+                .expect("couldn't spawn worker");
+        }
+        drop(done_tx);
+
+        // watchdog
+        // replies Error::TimedOut to requests exceeding their deadline
+        let running = Arc::new(AtomicBool::new(true));
+        let watchdog_thread = {
+            let watchdog = watchdog.clone();
+            let codec = codec.clone();
+            let cache = cache.clone();
+            let running = running.clone();
+            let transport = transport.clone();
+            thread::Builder::new()
+                .name("watchdog".to_string())
+                .spawn(move || {
+                    while running.load(Ordering::SeqCst) {
+                        thread::sleep(WATCHDOG_INTERVAL);
+                        for (i, job) in watchdog.expired() {
+                            log::error!("worker-{} stuck for {:?} in request from {:?}", i, job.start.elapsed(), &job.sender);
+                            match codec.encode_reply(handle_err(&Error::TimedOut, job.seq)) {
+                                Ok(x) => {
+                                    send(&*transport, &x, &job.sender);
+                                    if let Ok(mut cache) = cache.lock() {
+                                        cache.insert(&job.sender, &job.request, x);
+                                    }
+                                }
+                                Err(e) => log::error!("Failed to encode reply: {:?}", e),
+                            }
+                        }
+                    }
+                })
+                .expect("couldn't spawn watchdog")
+        };
+
+        // loop for UDP handling
+        // listens for messages on the transport,
+        // validates them and queues them for the workers
+        // requests are decoded in order of arrival, so the replay window sees increasing counters
+        // wake up regularly to check for shutdown
+        debug!("Start listener");
+        while !shutdown.load(Ordering::SeqCst) {
+            match transport.recv(SHUTDOWN_POLL) {
+                Ok(Some((b,a))) => {
+                    // answer retransmitted requests from the cache
+                    match cache.lock().map(|mut c| c.get(&a,&b)) {
+                        Ok(Lookup::Cached(x)) => {
+                            debug!("Resend cached reply: {:?} to {:?}",&x,&a);
+                            send(&*transport,&x,&a);
+                            continue;
+                        }
+                        Ok(Lookup::Pending) => {
+                            debug!("Drop retransmission of pending request from {:?}",&a);
+                            transport.drop_request(&a);
+                            continue;
+                        }
+                        _ => {}
+                    }
+                    // reject the request before it is checked against the replay window,
+                    // so the client may retry it
+                    if queued.load(Ordering::SeqCst) >= queue_depth {
+                        let seq = codec.decode_command(&b).ok().and_then(Frame::peek_seq).unwrap_or(0);
+                        match codec.encode_reply(handle_err(&Error::Busy, seq)) {
+                            Ok(x) => send(&*transport,&x,&a),
+                            Err(e) => log::error!("Failed to encode reply: {:?}", e),
+                        }
+                        continue;
+                    }
+                    match codec.decode_request(&b).map(|f| f.to_vec()) {
+                        Ok(f) => {
+                            if let Ok(mut cache) = cache.lock() {
+                                cache.begin(a.clone(),b.clone());
+                            }
+                            // only this thread adds to the queue, it cannot be full here
+                            queued.fetch_add(1, Ordering::SeqCst);
+                            if tx.try_send((b,f,a.clone())).is_err() {
+                                queued.fetch_sub(1, Ordering::SeqCst);
+                                log::error!("Failed to queue request from {:?}", &a);
+                                transport.drop_request(&a);
                             }
                         }
-                    // });
+                        Err(e) => {
+                            // the client matches the error to its request by the sequence number
+                            match codec.encode_reply(handle_err(&e, codec.peek_seq(&b).unwrap_or(0))) {
+                                Ok(x) => {
+                                    debug!("Send: {:?} to {:?}",&x,&a);
+                                    send(&*transport,&x,&a);
+                                }
+                                Err(e) => log::error!("Failed to encode reply: {:?}", e),
+                            }
+                        }
+                    }
                     continue;
                 }
-                Err(_) => continue,
+                Ok(None) => continue,
+                Err(e) => {
+                    log::error!("Failed to receive: {:?}", e);
+                    // don't spin on a failed link
+                    thread::sleep(SHUTDOWN_POLL);
+                }
+            };
+        }
+
+        // shutdown
+        // stop accepting requests, let the workers drain the queue
+        info!("Shutting down");
+        drop(tx);
+        let deadline = Instant::now() + shutdown_timeout;
+        let mut drained = true;
+        for _ in 0..workers {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if done_rx.recv_timeout(remaining).is_err() {
+                let stuck: Vec<usize> = (0..workers).filter(|i| watchdog.slots[*i].is_stuck()).collect();
+                log::error!("Workers did not finish within {:?}, stuck: {:?}", shutdown_timeout, stuck);
+                drained = false;
+                break;
+            }
+        }
+        running.store(false, Ordering::SeqCst);
+        let _ = watchdog_thread.join();
+
+        if let Some(hook) = &self.on_shutdown {
+            // a stuck worker may still hold the subsystem
+            let lock = match drained {
+                true => self.context.subsystem.write().ok(),
+                false => self.context.subsystem.try_write().ok(),
             };
+            match lock {
+                Some(mut s) => {
+                    if let Err(e) = hook(&mut s) {
+                        log::error!("Shutdown hook failed: {:?}", e);
+                    }
+                }
+                None => log::error!("Subsystem unavailable, skip shutdown hook"),
+            }
         }
+        info!("Service stopped");
+    }
+}
+
+/// Handle to a service started with `Service::spawn`
+pub struct ShutdownHandle {
+    addr: Address,
+    shutdown: Arc<AtomicBool>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl ShutdownHandle {
+    /// Socket address the service is listening on, if it uses an IP transport
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.addr.socket_addr()
+    }
+
+    /// Address of the transport the service is listening on
+    pub fn address(&self) -> &Address {
+        &self.addr
+    }
+
+    /// Stops the service and waits until it has shut down
+    pub fn shutdown(self) -> Result<()> {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.thread.join().map_err(|_| Error::Failure("Service panicked".to_string()))
+    }
+}
+
+// Configuration and transport of a started service
+struct Server {
+    transport: Arc<dyn Transport>,
+    codec: Codec,
+    cache: Arc<Mutex<ReplyCache>>,
+    workers: usize,
+    queue_depth: usize,
+    watchdog: Arc<Watchdog>,
+    shutdown_timeout: Duration,
+}
+
+// Reads the time the workers get to finish on shutdown from the service's config section
+pub(super) fn shutdown_timeout_from_config(config: &Config) -> Result<Duration> {
+    match config.get("shutdown_timeout").map(|v| v.as_integer()) {
+        Some(Some(t)) if t >= 0 => Ok(Duration::from_millis(t as u64)),
+        Some(_) => Err(Error::InvalidConfig("shutdown_timeout".to_string())),
+        None => Ok(DEFAULT_SHUTDOWN_TIMEOUT),
+    }
+}
+
+// Reads size of the worker pool and request queue from the service's config section
+pub(super) fn pool_from_config(config: &Config) -> Result<(usize, usize)> {
+    let workers = match config.get("workers").map(|v| v.as_integer()) {
+        Some(Some(w)) if w > 0 => w as usize,
+        Some(_) => return Err(Error::InvalidConfig("workers".to_string())),
+        None => DEFAULT_WORKERS,
+    };
+    let queue_depth = match config.get("queue_depth").map(|v| v.as_integer()) {
+        Some(Some(d)) if d > 0 => d as usize,
+        Some(_) => return Err(Error::InvalidConfig("queue_depth".to_string())),
+        None => DEFAULT_QUEUE_DEPTH,
+    };
+    Ok((workers, queue_depth))
+}
+
+// Sends a reply, a failed reply is only logged as the client retries or times out
+fn send(transport: &dyn Transport, msg: &[u8], to: &Address) {
+    if let Err(e) = transport.send(msg, to) {
+        log::error!("Failed to send to {}: {:?}", to, e);
     }
 }
 
 // Helper function to handle Errors
 // 
-// Returns [0,0] instead of CommandID, 
-// or [255,255] if another error occured within this function
-fn handle_err(err: &Error) -> Vec<u8>{
+// Returns a frame with CommandID 0 carrying the Error, 
+// or CommandID 65535 if another error occured within this function.
+// `seq` is the sequence number of the failed request.
+pub(super) fn handle_err(err: &Error, seq: u16) -> Vec<u8>{
     debug!("Handle Error");
-    let mut buf: Vec<u8> = Vec::new();
-    match bincode::serialize(err) {
-        Ok(mut k) => {
-            buf.append(&mut [0,0].to_vec());
-            buf.append(&mut k);
-        }
-        Err(b) => {
-            buf.append(&mut [255,255].to_vec());
-            buf.push(from_bincode_error(b));
-        }
-    }
-    buf
+    let frame = match bincode::serialize(err) {
+        Ok(k) => Frame::encode(ERROR_ID, seq, &k),
+        Err(b) => Frame::encode(FATAL_ID, seq, &[from_bincode_error(b)]),
+    };
+    // an encoded Error never exceeds MAX_PAYLOAD
+    frame.unwrap_or_default()
 }
 
 fn from_bincode_error(b: bincode::Error) -> u8 {
@@ -187,3 +541,66 @@ fn from_bincode_error(b: bincode::Error) -> u8 {
         bincode::ErrorKind::Custom(_) => 8,            
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Checksum;
+    use std::net::UdpSocket;
+
+    // Sends `request` and returns the error and sequence number of the reply
+    fn rejected(socket: &UdpSocket, codec: &Codec, request: &[u8]) -> (Error, u16) {
+        socket.send(request).unwrap();
+        let mut buf = vec![0u8; u16::MAX as usize];
+        let n = socket.recv(&mut buf).unwrap();
+        let frame = Frame::parse(codec.decode(&buf[..n]).unwrap()).unwrap();
+        assert!(frame.is_error());
+        (frame.error(), frame.seq)
+    }
+
+    #[test]
+    fn rejection_carries_seq() {
+        let cfg = "[svc]\nchecksum = \"crc16\"\n[svc.addr]\nip = \"127.0.0.1\"\nport = 0\n";
+        let echo = |_: &RwLock<u8>, frame: &mut Vec<u8>| Ok(frame.clone());
+        let h = Service::new(Config::new_from_str("svc", cfg).unwrap(), 0u8, Some(Arc::new(echo))).spawn().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        socket.connect(h.local_addr().unwrap()).unwrap();
+        let codec = Codec { checksum: Checksum::Crc16, ..Codec::default() };
+        let mut request = codec.encode(Frame::encode(7, 0x1234, &[1, 2, 3]).unwrap()).unwrap();
+        *request.last_mut().unwrap() ^= 1;
+        assert_eq!(rejected(&socket, &codec, &request), (Error::Checksum, 0x1234));
+        h.shutdown().unwrap();
+    }
+
+    #[test]
+    fn auth_rejection_carries_seq() {
+        let dir = std::env::temp_dir().join(format!("cubeos-udp-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cfg = format!(
+            "[svc]\nauth_key = \"{}\"\nauth_key_id = 1\nauth_counter = \"{}\"\nreplay_state = \"{}\"\n[svc.addr]\nip = \"127.0.0.1\"\nport = 0\n",
+            "07".repeat(16),
+            dir.join("counter").display(),
+            dir.join("replay").display(),
+        );
+        let echo = |_: &RwLock<u8>, frame: &mut Vec<u8>| Ok(frame.clone());
+        let h = Service::new(Config::new_from_str("svc", &cfg).unwrap(), 0u8, Some(Arc::new(echo))).spawn().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        socket.connect(h.local_addr().unwrap()).unwrap();
+        let codec = Codec { auth: Some(crate::auth::Key::new(1, vec![7; 16]).unwrap()), ..Codec::default() };
+        let mut forged = codec.encode(Frame::encode(7, 0x0101, &[]).unwrap()).unwrap();
+        *forged.last_mut().unwrap() ^= 1;
+        assert_eq!(rejected(&socket, &codec, &forged), (Error::Unauthenticated, 0x0101));
+        // a command with a counter below the last accepted one
+        let old = codec.encode(Frame::encode(7, 0x0201, &[]).unwrap()).unwrap();
+        let new = codec.encode(Frame::encode(7, 0x0202, &[]).unwrap()).unwrap();
+        socket.send(&new).unwrap();
+        let mut buf = vec![0u8; u16::MAX as usize];
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(Frame::parse(codec.decode(&buf[..n]).unwrap()).unwrap().seq, 0x0202);
+        assert_eq!(rejected(&socket, &codec, &old), (Error::Replay, 0x0201));
+        h.shutdown().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -17,42 +17,143 @@
 // 
 
 // Command-ID macro
+//
+// The entries are parsed one by one into the form
+// `{ kind lock Type (id) func [arg: Arg, ..] (timeout) Reply }`,
+// because the optional clauses at the end of an entry cannot be told apart
+// from the start of the next entry in a single pattern. `lock` is `query`
+// or `mutation` depending on the receiver of the function.
+// The parsed entries are generated into the synchronous handler, or with the
+// `async` mode into the handler of `async_service_macro!`.
 #[macro_export]
-macro_rules! service_macro {
+macro_rules! udp_service_macro {
+    // subsystem given as a type, used by #[cubeos_service]
+    (
+        use $error: ty;
+        impl $sub: ty {
+            $($entries: tt)*
+        }
+    ) => {
+        $crate::udp_service_macro!{@parse [sync; $error; $sub] [] $($entries)*}
+    };
     (
         use $error: ty;
         $krate: tt ::$strukt: tt {
-            $(
-                $(query)?$(mutation)?: $type: ident => fn $func: tt (&$(mut )?self $(,$ign0: tt: $cmd: ty)*) -> $ign1: tt<$rep: ty> $(; out: $gql_q: ty)?;
-            )*
+            $($entries: tt)*
         }
     ) => {
-        use cubeos_service::command_id;
+        $crate::udp_service_macro!{@parse [sync; $error; crate::$krate::$strukt] [] $($entries)*}
+    };
+    (@parse $head: tt $parsed: tt
+        $kind: ident: $type: ident $(= $id: literal)? => fn $func: tt $params: tt -> $ign1: tt<$rep: ty>; out: $gql_q: ty; timeout: $timeout: literal;
+        $($rest: tt)*
+    ) => {
+        $crate::udp_service_macro!{@receiver $head $parsed $params {$kind $type ($($id)?) $func ($timeout) $rep} $($rest)*}
+    };
+    (@parse $head: tt $parsed: tt
+        $kind: ident: $type: ident $(= $id: literal)? => fn $func: tt $params: tt -> $ign1: tt<$rep: ty>; out: $gql_q: ty;
+        $($rest: tt)*
+    ) => {
+        $crate::udp_service_macro!{@receiver $head $parsed $params {$kind $type ($($id)?) $func () $rep} $($rest)*}
+    };
+    (@parse $head: tt $parsed: tt
+        $kind: ident: $type: ident $(= $id: literal)? => fn $func: tt $params: tt -> $ign1: tt<$rep: ty>; timeout: $timeout: literal;
+        $($rest: tt)*
+    ) => {
+        $crate::udp_service_macro!{@receiver $head $parsed $params {$kind $type ($($id)?) $func ($timeout) $rep} $($rest)*}
+    };
+    (@parse $head: tt $parsed: tt
+        $kind: ident: $type: ident $(= $id: literal)? => fn $func: tt $params: tt -> $ign1: tt<$rep: ty>;
+        $($rest: tt)*
+    ) => {
+        $crate::udp_service_macro!{@receiver $head $parsed $params {$kind $type ($($id)?) $func () $rep} $($rest)*}
+    };
+    // the lock is chosen by the receiver of the function,
+    // a query may take `&mut self` and then locks the subsystem for writing
+    (@receiver $head: tt [$($parsed: tt)*] (&self $(,$arg: tt: $cmd: ty)*)
+        {$kind: ident $type: ident ($($id: literal)?) $func: tt ($($timeout: literal)?) $rep: ty}
+        $($rest: tt)*
+    ) => {
+        $crate::udp_service_macro!{@parse $head [$($parsed)* {$kind query $type ($($id)?) $func [$($arg: $cmd),*] ($($timeout)?) $rep}] $($rest)*}
+    };
+    (@receiver $head: tt [$($parsed: tt)*] (&mut self $(,$arg: tt: $cmd: ty)*)
+        {$kind: ident $type: ident ($($id: literal)?) $func: tt ($($timeout: literal)?) $rep: ty}
+        $($rest: tt)*
+    ) => {
+        $crate::udp_service_macro!{@parse $head [$($parsed)* {$kind mutation $type ($($id)?) $func [$($arg: $cmd),*] ($($timeout)?) $rep}] $($rest)*}
+    };
+    (@receiver $head: tt $parsed: tt $params: tt {$kind: ident $type: ident $($ign: tt)*} $($rest: tt)*) => {
+        compile_error!(concat!("command ", stringify!($type), " must take `&self` or `&mut self` as first argument"));
+    };
+    (@parse [async; $error: ty; $sub: ty] $parsed: tt) => {
+        $crate::async_service_macro!{@generate [$error; $sub] $parsed}
+    };
+    (@parse [sync; $error: ty; $sub: ty] [$(
+        {$kind: ident $lock: ident $type: ident ($($id: literal)?) $func: tt [$($arg: tt: $cmd: ty),*] ($($timeout: literal)?) $rep: ty}
+    )*]) => {
+        use cubeos_service::{command_id,Frame};
         use std::env::Args;
         use std::str::FromStr;
         use log::debug;
-        use crate::$krate::$strukt as Subsystem;
+        type Subsystem = $sub;
 
         command_id!{
-            // Ping,
-            // LastCmd,
-            // LastErr,
-            $($type,)*
+            $($type $(= $id)?,)*
         }
 
+        // hash of the command table, replied to the built-in Interface command
+        pub const INTERFACE_HASH: u64 = cubeos_service::interface_hash(&[$(
+            cubeos_service::command_hash(stringify!($type), CommandID::$type.id(), &[$(stringify!($cmd)),*], stringify!($rep)),
+        )*]);
+
         // UDP handler function running on the service
         // takes incoming msg and parses it into CommandID and Command for msg handling
-        pub fn udp_handler(sub: &mut Box<Subsystem>, msg: &mut Vec<u8>) -> CubeOSResult<Vec<u8>> {
+        // functions taking `&self` lock the subsystem for reading, `&mut self` for writing
+        // errors are recorded as the last error of the service
+        pub fn udp_handler(sub: &std::sync::RwLock<Box<Subsystem>>, msg: &mut Vec<u8>) -> CubeOSResult<Vec<u8>> {
+            #[allow(unused_imports)]
+            use cubeos_service::{Builtin,LastBuiltin,LastDefault};
             debug!("Message: {:?}",msg);
 
-            // Verify CommandID            
-            match CommandID::try_from(u16::from_be_bytes([msg[0],msg[1]]))? {          
+            let result = udp_dispatch(sub, msg);
+            if let Err(e) = &result {
+                let s = sub.read().map_err(|_| CubeOSError::PoisonedRwLock)?;
+                (&&Builtin(&**s)).builtin_set_last_err(e.clone());
+            }
+            result
+        }
+
+        // description of all commands of the service
+        pub fn describe() -> CubeOSResult<Vec<cubeos_service::CommandInfo>> {
+            let mut commands = cubeos_service::builtin_commands();
+            $(commands.push(cubeos_service::CommandInfo::new(
+                stringify!($type),
+                u16::try_from(CommandID::$type)?,
+                $crate::command_kind!($kind),
+                &[$((stringify!($arg), stringify!($cmd))),*],
+                stringify!($rep),
+            ));)*
+            Ok(commands)
+        }
+
+        fn udp_dispatch(sub: &std::sync::RwLock<Box<Subsystem>>, msg: &Vec<u8>) -> CubeOSResult<Vec<u8>> {
+            #[allow(unused_imports)]
+            use cubeos_service::{Builtin,PingBuiltin,PingDefault,LastBuiltin,LastDefault};
+
+            // Validate frame and CommandID
+            let frame = Frame::parse(msg)?;
+            match CommandID::try_from(frame.id)? {          
                 $(CommandID::$type => {
-                    let data = bincode::deserialize::<($($cmd),*)>(&msg[2..])?;
-                    match run!(Subsystem::$func; sub, data $(,$cmd)*) {
+                    let mut args = frame.args();
+                    $(let $arg = args.next::<$cmd>()?;)*
+                    args.finish()?;
+                    // the timeout includes waiting for the lock
+                    $(cubeos_service::arm_watchdog(std::time::Duration::from_millis($timeout));)?
+                    $crate::subsystem_lock!($lock, sub, guard, s);
+                    (&&Builtin(&**s)).builtin_set_last_cmd(msg.clone());
+                    match Subsystem::$func(s $(,$arg)*) {
                         Ok(x) => {                            
-                            let mut r = <u16>::try_from(CommandID::$type)?.to_be_bytes().to_vec();
-                            r.append(&mut bincode::serialize(&x)?);                            
+                            let r = Frame::encode(<u16>::try_from(CommandID::$type)?, frame.seq, &bincode::serialize(&x)?)?;
                             debug!("Reply: {:?}",r);
                             Ok(r)
                         }
@@ -61,6 +162,39 @@ macro_rules! service_macro {
                         }
                     }
                 },)* 
+                // built-in commands
+                CommandID::Ping => {
+                    frame.data::<()>()?;
+                    $crate::subsystem_lock!(query, sub, guard, s);
+                    let x = (&&Builtin(&**s)).builtin_ping()?;
+                    Frame::encode(cubeos_service::PING_ID, frame.seq, &bincode::serialize(&x)?)
+                },
+                CommandID::LastCmd => {
+                    frame.data::<()>()?;
+                    $crate::subsystem_lock!(query, sub, guard, s);
+                    let x = (&&Builtin(&**s)).builtin_get_last_cmd()?;
+                    Frame::encode(cubeos_service::LAST_CMD_ID, frame.seq, &bincode::serialize(&x)?)
+                },
+                CommandID::LastErr => {
+                    frame.data::<()>()?;
+                    $crate::subsystem_lock!(query, sub, guard, s);
+                    let x = (&&Builtin(&**s)).builtin_get_last_err()?;
+                    Frame::encode(cubeos_service::LAST_ERR_ID, frame.seq, &bincode::serialize(&x)?)
+                },
+                CommandID::LastHistory => {
+                    frame.data::<()>()?;
+                    $crate::subsystem_lock!(query, sub, guard, s);
+                    let x = (&&Builtin(&**s)).builtin_get_last_history()?;
+                    Frame::encode(cubeos_service::LAST_HISTORY_ID, frame.seq, &bincode::serialize(&x)?)
+                },
+                CommandID::Describe => {
+                    frame.data::<()>()?;
+                    Frame::encode(cubeos_service::DESCRIBE_ID, frame.seq, &bincode::serialize(&describe()?)?)
+                },
+                CommandID::Interface => {
+                    frame.data::<()>()?;
+                    Frame::encode(cubeos_service::INTERFACE_ID, frame.seq, &bincode::serialize(&INTERFACE_HASH)?)
+                },
             }
         }
 
@@ -76,45 +210,22 @@ macro_rules! service_macro {
     };
 }
 
+// Locks the subsystem for a query or mutation
+// and binds the guard and the reference to the subsystem
 #[macro_export]
-macro_rules! count {
-    () => (0usize);
-    ( $x:tt $($xs:tt)* ) => (1usize + count!($($xs)*));
+macro_rules! subsystem_lock {
+    (query, $lock: expr, $guard: ident, $sub: ident) => {
+        let $guard = $lock.read().map_err(|_| $crate::Error::PoisonedRwLock)?;
+        let $sub = &*$guard;
+    };
+    (mutation, $lock: expr, $guard: ident, $sub: ident) => {
+        let mut $guard = $lock.write().map_err(|_| $crate::Error::PoisonedRwLock)?;
+        let $sub = &mut *$guard;
+    };
 }
 
 #[macro_export]
-macro_rules! run {    
-    // 0 input parameter
-    ($f: expr; $sub: tt, $in: tt)
-        => {$f($sub)};
-    // 1 input parameter
-    ($f: expr; $sub: tt, $in: tt, $_cmd: tt)
-        => {$f($sub,$in)};
-    // 2 input parameter        
-    ($f: expr; $sub: tt, $in: tt, $_cmd: tt, $_cmd2: tt) 
-        => {$f($sub,$in.0,$in.1)};
-    // 3 input parameter
-    ($f: expr; $sub: tt, $in: tt, $_cmd: tt, $_cmd2: tt, $_cmd3: tt) 
-        => {$f($sub,$in.0,$in.1,$in.2)};
-    // 4 input parameter
-    ($f: expr; $sub: tt, $in: tt, $_cmd: tt, $_cmd2: tt, $_cmd3: tt, $_cmd4: tt) 
-        => {$f($sub,$in.0,$in.1,$in.2,$in.3)};
-    // 5 input parameter
-    ($f: expr; $sub: tt, $in: tt, $_cmd: tt, $_cmd2: tt, $_cmd3: tt, $_cmd4: tt, $_cmd5: tt) 
-        => {$f($sub,$in.0,$in.1,$in.2,$in.3,$in.4)};
-    // 6 input parameter
-    ($f: expr; $sub: tt, $in: tt, $_cmd: tt, $_cmd2: tt, $_cmd3: tt, $_cmd4: tt, $_cmd5: tt, $_cmd6: tt) 
-        => {$f($sub,$in.0,$in.1,$in.2,$in.3,$in.4,$in.5)};
-    // 7 input parameter
-    ($f: expr; $sub: tt, $in: tt, $_cmd: tt, $_cmd2: tt, $_cmd3: tt, $_cmd4: tt, $_cmd5: tt, $_cmd6: tt, $_cmd7: tt) 
-        => {$f($sub,$in.0,$in.1,$in.2,$in.3,$in.4,$in.5,$in.6)};
-    // 8 input parameter
-    ($f: expr; $sub: tt, $in: tt, $_cmd: tt, $_cmd2: tt, $_cmd3: tt, $_cmd4: tt, $_cmd5: tt, $_cmd6: tt, $_cmd7: tt, $_cmd8: tt) 
-        => {$f($sub,$in.0,$in.1,$in.2,$in.3,$in.4,$in.5,$in.6,$in.7)};
-    // 9 input parameter
-    ($f: expr; $sub: tt, $in: tt, $_cmd: tt, $_cmd2: tt, $_cmd3: tt, $_cmd4: tt, $_cmd5: tt, $_cmd6: tt, $_cmd7: tt, $_cmd8: tt, $_cmd9: tt) 
-        => {$f($sub,$in.0,$in.1,$in.2,$in.3,$in.4,$in.5,$in.6,$in.7,$in.8)};
-    // 10 input parameter
-    ($f: expr; $sub: tt, $in: tt, $_cmd: tt, $_cmd2: tt, $_cmd3: tt, $_cmd4: tt, $_cmd5: tt, $_cmd6: tt, $_cmd7: tt, $_cmd8: tt, $_cmd9: tt, $_cmd10: tt) 
-        => {$f($sub,$in.0,$in.1,$in.2,$in.3,$in.4,$in.5,$in.6,$in.7,$in.8,$in.9)};
+macro_rules! count {
+    () => (0usize);
+    ( $x:tt $($xs:tt)* ) => (1usize + count!($($xs)*));
 }
@@ -0,0 +1,154 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Contributed by: Patrick Oppel (patrick.oppel94@gmail.com)
+//
+
+// Watchdog for the execution time of commands
+//
+// Every worker announces the request it executes in its `Slot`.
+// The deadline is the service-wide `command_timeout`, which the generated
+// `udp_handler` may replace with the timeout declared for the command.
+// If a request exceeds its deadline, the watchdog replies `Error::TimedOut`
+// to the client and flags the worker as stuck until the call returns.
+//
+// ```toml,ignore
+// [service-name]
+// command_timeout = 2000  # milliseconds, no timeout if not set
+// ```
+
+use crate::address::Address;
+use crate::error::*;
+use kubos_system::Config;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Interval in which the watchdog checks the deadlines
+pub const WATCHDOG_INTERVAL: Duration = Duration::from_millis(10);
+
+thread_local! {
+    // slot of the worker running on this thread
+    static CURRENT: RefCell<Option<Arc<Slot>>> = RefCell::new(None);
+}
+
+/// Request executed by a worker
+pub struct Job {
+    pub sender: Address,
+    /// Request as received, used as key of the reply cache
+    pub request: Vec<u8>,
+    pub seq: u16,
+    pub start: Instant,
+    pub deadline: Option<Instant>,
+}
+
+/// Execution state of a worker
+#[derive(Default)]
+pub struct Slot {
+    job: Mutex<Option<Job>>,
+    stuck: AtomicBool,
+}
+
+impl Slot {
+    /// Announces the request executed by the worker on the current thread
+    pub fn begin(self: &Arc<Self>, job: Job) {
+        if let Ok(mut j) = self.job.lock() {
+            *j = Some(job);
+        }
+        CURRENT.with(|c| *c.borrow_mut() = Some(self.clone()));
+    }
+
+    /// Clears the request and returns false if the watchdog already replied to it
+    pub fn finish(&self) -> bool {
+        let job = self.job.lock().ok().and_then(|mut j| j.take());
+        // the worker has recovered
+        self.stuck.store(false, Ordering::SeqCst);
+        job.is_some()
+    }
+
+    /// Returns true while the worker executes a request past its deadline
+    pub fn is_stuck(&self) -> bool {
+        self.stuck.load(Ordering::SeqCst)
+    }
+
+    // Takes the request if it has exceeded its deadline
+    fn expire(&self, now: Instant) -> Option<Job> {
+        let mut job = self.job.lock().ok()?;
+        match job.as_ref().and_then(|j| j.deadline) {
+            Some(d) if d <= now => {
+                self.stuck.store(true, Ordering::SeqCst);
+                job.take()
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Sets the timeout of the request executed on the current thread
+///
+/// Called by the generated `udp_handler` for commands with a declared timeout.
+/// Has no effect outside of a worker of the `Service`.
+pub fn arm_watchdog(timeout: Duration) {
+    CURRENT.with(|c| {
+        if let Some(slot) = c.borrow().as_ref() {
+            if let Ok(mut job) = slot.job.lock() {
+                if let Some(j) = job.as_mut() {
+                    j.deadline = Some(j.start + timeout);
+                }
+            }
+        }
+    })
+}
+
+/// Slots of all workers of a service
+pub struct Watchdog {
+    pub slots: Vec<Arc<Slot>>,
+    /// Service-wide timeout of a command
+    pub timeout: Option<Duration>,
+}
+
+impl Watchdog {
+    pub fn new(workers: usize, timeout: Option<Duration>) -> Self {
+        Watchdog {
+            slots: (0..workers).map(|_| Arc::new(Slot::default())).collect(),
+            timeout,
+        }
+    }
+
+    /// Reads the service-wide timeout from the service's config section
+    pub fn from_config(config: &Config, workers: usize) -> Result<Self> {
+        Ok(Watchdog::new(workers, command_timeout_from_config(config)?))
+    }
+
+    /// Takes all requests that exceeded their deadline, with the index of their worker
+    pub fn expired(&self) -> Vec<(usize, Job)> {
+        let now = Instant::now();
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(i, s)| s.expire(now).map(|j| (i, j)))
+            .collect()
+    }
+}
+
+/// Reads the service-wide timeout of a command from the service's config section
+pub fn command_timeout_from_config(config: &Config) -> Result<Option<Duration>> {
+    match config.get("command_timeout").map(|v| v.as_integer()) {
+        Some(Some(t)) if t > 0 => Ok(Some(Duration::from_millis(t as u64))),
+        Some(_) => Err(Error::InvalidConfig("command_timeout".to_string())),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,285 @@
+//
+// Copyright (C) 2022 CUAVA
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Contributed by: Patrick Oppel (patrick.oppel94@gmail.com)
+//
+
+// CCSDS Space Packet (CCSDS 133.0-B-2) encoding of Command frames
+//
+// | primary header (6) | secondary header (6, optional) | Command frame | packet error control (optional) |
+//
+// The secondary header carries a CUC timestamp with 4 byte coarse time (seconds)
+// and 2 byte fine time (1/65536 s). The agency defined epoch is the Unix epoch.
+
+use crate::error::*;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Length of the primary header in bytes
+pub const PRIMARY_HEADER_LEN: usize = 6;
+/// Length of the timestamp secondary header in bytes
+pub const SECONDARY_HEADER_LEN: usize = 6;
+/// Largest APID, 0x7FF is reserved for idle packets
+pub const MAX_APID: u16 = 0x7FE;
+
+const SEQ_COUNT_MASK: u16 = 0x3FFF;
+
+// Source sequence count of all packets sent by this process
+static SEQ_COUNT: AtomicU16 = AtomicU16::new(0);
+
+/// Packet type field of the primary header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PacketType {
+    /// Replies sent by a service
+    Telemetry = 0,
+    /// Commands sent to a service
+    Telecommand = 1,
+}
+
+/// Sequence flags field of the primary header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SequenceFlags {
+    Continuation = 0,
+    First = 1,
+    Last = 2,
+    Unsegmented = 3,
+}
+impl From<u8> for SequenceFlags {
+    fn from(f: u8) -> Self {
+        match f & 0b11 {
+            0 => SequenceFlags::Continuation,
+            1 => SequenceFlags::First,
+            2 => SequenceFlags::Last,
+            _ => SequenceFlags::Unsegmented,
+        }
+    }
+}
+
+/// Space Packet primary header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PrimaryHeader {
+    pub packet_type: PacketType,
+    pub sec_header: bool,
+    pub apid: u16,
+    pub seq_flags: SequenceFlags,
+    pub seq_count: u16,
+    /// Length of the packet data field minus 1
+    pub data_len: u16,
+}
+impl PrimaryHeader {
+    pub fn to_bytes(&self) -> [u8; PRIMARY_HEADER_LEN] {
+        let id = ((self.packet_type as u16) << 12)
+            | ((self.sec_header as u16) << 11)
+            | (self.apid & 0x07FF);
+        let seq = ((self.seq_flags as u16) << 14) | (self.seq_count & SEQ_COUNT_MASK);
+        let id = id.to_be_bytes();
+        let seq = seq.to_be_bytes();
+        let len = self.data_len.to_be_bytes();
+        [id[0], id[1], seq[0], seq[1], len[0], len[1]]
+    }
+
+    pub fn parse(msg: &[u8]) -> Result<PrimaryHeader> {
+        if msg.len() < PRIMARY_HEADER_LEN {
+            return Err(Error::Truncated);
+        }
+        let id = u16::from_be_bytes([msg[0], msg[1]]);
+        let seq = u16::from_be_bytes([msg[2], msg[3]]);
+        // packet version number must be 0
+        if id >> 13 != 0 {
+            return Err(Error::InvalidPacket);
+        }
+        Ok(PrimaryHeader {
+            packet_type: match (id >> 12) & 1 {
+                0 => PacketType::Telemetry,
+                _ => PacketType::Telecommand,
+            },
+            sec_header: (id >> 11) & 1 == 1,
+            apid: id & 0x07FF,
+            seq_flags: SequenceFlags::from((seq >> 14) as u8),
+            seq_count: seq & SEQ_COUNT_MASK,
+            data_len: u16::from_be_bytes([msg[4], msg[5]]),
+        })
+    }
+}
+
+/// CUC timestamp carried in the secondary header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timestamp {
+    pub seconds: u32,
+    pub subseconds: u16,
+}
+impl Timestamp {
+    pub fn now() -> Timestamp {
+        let t = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Timestamp {
+            seconds: t.as_secs() as u32,
+            subseconds: ((t.subsec_nanos() as u64 * 65536) / 1_000_000_000) as u16,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; SECONDARY_HEADER_LEN] {
+        let s = self.seconds.to_be_bytes();
+        let f = self.subseconds.to_be_bytes();
+        [s[0], s[1], s[2], s[3], f[0], f[1]]
+    }
+
+    pub fn parse(msg: &[u8]) -> Result<Timestamp> {
+        if msg.len() < SECONDARY_HEADER_LEN {
+            return Err(Error::Truncated);
+        }
+        Ok(Timestamp {
+            seconds: u32::from_be_bytes([msg[0], msg[1], msg[2], msg[3]]),
+            subseconds: u16::from_be_bytes([msg[4], msg[5]]),
+        })
+    }
+}
+
+/// Validated view into a received Space Packet
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpacePacket<'a> {
+    pub header: PrimaryHeader,
+    pub timestamp: Option<Timestamp>,
+    /// User data field, i.e. the Command frame
+    pub data: &'a [u8],
+}
+
+impl<'a> SpacePacket<'a> {
+    /// Validates `msg` and returns the contained packet
+    ///
+    /// `pec_len` is the length of the packet error control field
+    /// at the end of the data field, which is not part of `data`.
+    pub fn parse(msg: &'a [u8], pec_len: usize) -> Result<SpacePacket<'a>> {
+        let header = PrimaryHeader::parse(msg)?;
+        let len = PRIMARY_HEADER_LEN + header.data_len as usize + 1;
+        if msg.len() < len {
+            return Err(Error::Truncated);
+        }
+        if msg.len() > len {
+            return Err(Error::TrailingBytes);
+        }
+        if header.seq_flags != SequenceFlags::Unsegmented {
+            return Err(Error::InvalidPacket);
+        }
+        let mut start = PRIMARY_HEADER_LEN;
+        let timestamp = match header.sec_header {
+            true => {
+                start += SECONDARY_HEADER_LEN;
+                Some(Timestamp::parse(&msg[PRIMARY_HEADER_LEN..])?)
+            }
+            false => None,
+        };
+        if msg.len() < start + pec_len {
+            return Err(Error::Truncated);
+        }
+        Ok(SpacePacket {
+            header,
+            timestamp,
+            data: &msg[start..msg.len() - pec_len],
+        })
+    }
+
+    /// Builds an unsegmented packet around `data`
+    ///
+    /// The packet data length accounts for a packet error control field of
+    /// `pec_len` bytes, which has to be appended by the caller.
+    /// Returns `Error::InvalidPacket` if the packet data field would be empty.
+    pub fn encode(
+        packet_type: PacketType,
+        apid: u16,
+        timestamp: Option<Timestamp>,
+        data: &[u8],
+        pec_len: usize,
+    ) -> Result<Vec<u8>> {
+        if apid > MAX_APID {
+            return Err(Error::InvalidPacket);
+        }
+        let sec_len = match timestamp {
+            Some(_) => SECONDARY_HEADER_LEN,
+            None => 0,
+        };
+        let data_len = sec_len + data.len() + pec_len;
+        // the packet data field holds at least one byte
+        if data_len == 0 {
+            return Err(Error::InvalidPacket);
+        }
+        if data_len > u16::MAX as usize + 1 {
+            return Err(Error::Oversized);
+        }
+        let header = PrimaryHeader {
+            packet_type,
+            sec_header: timestamp.is_some(),
+            apid,
+            seq_flags: SequenceFlags::Unsegmented,
+            seq_count: SEQ_COUNT.fetch_add(1, Ordering::Relaxed) & SEQ_COUNT_MASK,
+            data_len: (data_len - 1) as u16,
+        };
+        let mut buf: Vec<u8> = Vec::with_capacity(PRIMARY_HEADER_LEN + data_len);
+        buf.extend_from_slice(&header.to_bytes());
+        if let Some(t) = timestamp {
+            buf.extend_from_slice(&t.to_bytes());
+        }
+        buf.extend_from_slice(data);
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_layout() {
+        let header = PrimaryHeader {
+            packet_type: PacketType::Telecommand,
+            sec_header: true,
+            apid: 0x123,
+            seq_flags: SequenceFlags::Unsegmented,
+            seq_count: 0x0ABC,
+            data_len: 0x0102,
+        };
+        assert_eq!(header.to_bytes(), [0x19, 0x23, 0xCA, 0xBC, 0x01, 0x02]);
+        assert_eq!(PrimaryHeader::parse(&header.to_bytes()).unwrap(), header);
+    }
+
+    #[test]
+    fn round_trip() {
+        let timestamp = Timestamp { seconds: 1_700_000_000, subseconds: 0x8000 };
+        let mut msg = SpacePacket::encode(PacketType::Telemetry, 100, Some(timestamp), &[1, 2, 3], 2).unwrap();
+        assert_eq!(msg.len(), PRIMARY_HEADER_LEN + SECONDARY_HEADER_LEN + 3);
+        msg.extend_from_slice(&[0, 0]);
+        let packet = SpacePacket::parse(&msg, 2).unwrap();
+        assert_eq!(packet.header.packet_type, PacketType::Telemetry);
+        assert_eq!(packet.header.apid, 100);
+        assert_eq!(packet.header.data_len as usize, SECONDARY_HEADER_LEN + 3 + 2 - 1);
+        assert_eq!(packet.timestamp, Some(timestamp));
+        assert_eq!(packet.data, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn invalid_input() {
+        let msg = SpacePacket::encode(PacketType::Telecommand, 7, None, &[1, 2, 3], 0).unwrap();
+        assert_eq!(SpacePacket::parse(&msg[..4], 0), Err(Error::Truncated));
+        assert_eq!(SpacePacket::parse(&msg[..msg.len() - 1], 0), Err(Error::Truncated));
+        assert_eq!(SpacePacket::parse(&[&msg[..], &[0]].concat(), 0), Err(Error::TrailingBytes));
+        assert_eq!(SpacePacket::parse(&msg, 4), Err(Error::Truncated));
+        let mut version = msg.clone();
+        version[0] |= 0x20;
+        assert_eq!(SpacePacket::parse(&version, 0), Err(Error::InvalidPacket));
+        assert_eq!(SpacePacket::encode(PacketType::Telecommand, 7, None, &[], 0), Err(Error::InvalidPacket));
+        assert_eq!(SpacePacket::encode(PacketType::Telecommand, 0x7FF, None, &[1], 0), Err(Error::InvalidPacket));
+    }
+}